@@ -436,7 +436,7 @@ fn run(matches: &ArgMatches, buff_log: &buff_log::Handle<env_logger::Logger>) ->
                     }
                     nix::libc::SIGINT => {
                         info!("SIGINT received, exiting");
-                        return Ok(());
+                        return engine.borrow_mut().shutdown();
                     }
                     signo => {
                         panic!("Caught an impossible signal {:?}", signo);
@@ -41,7 +41,13 @@ fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let object_path = m.path.get_name();
     let dbus_context = m.tree.get_data();
     let mut engine = dbus_context.engine.borrow_mut();
-    let result = engine.create_pool(name, &blockdevs, tuple_to_option(redundancy), force);
+    let result = engine.create_pool(
+        name,
+        &blockdevs,
+        tuple_to_option(redundancy),
+        force,
+        None,
+    );
 
     let return_message = message.method_return();
 
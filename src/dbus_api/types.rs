@@ -27,6 +27,9 @@ macro_attr! {
         INTERNAL_ERROR,
         NIX_ERROR,
         NOTFOUND,
+        CORRUPT,
+        NOT_ENOUGH_SPACE,
+        POLICY_VIOLATION,
     }
 }
 
@@ -48,6 +51,9 @@ impl DbusErrorEnum {
             DbusErrorEnum::IO_ERROR => "IO error during operation",
             DbusErrorEnum::NIX_ERROR => "System error during operation",
             DbusErrorEnum::NOTFOUND => "Not found",
+            DbusErrorEnum::CORRUPT => "Data on a device is corrupt",
+            DbusErrorEnum::NOT_ENOUGH_SPACE => "Not enough space for the operation",
+            DbusErrorEnum::POLICY_VIOLATION => "Operation is forbidden by policy",
         }
     }
 }
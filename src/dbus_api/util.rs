@@ -50,6 +50,9 @@ pub fn engine_to_dbus_err_tuple(err: &StratisError) -> (u16, String) {
             ErrorEnum::Busy => DbusErrorEnum::BUSY,
             ErrorEnum::Invalid => DbusErrorEnum::ERROR,
             ErrorEnum::NotFound => DbusErrorEnum::NOTFOUND,
+            ErrorEnum::Corrupt => DbusErrorEnum::CORRUPT,
+            ErrorEnum::NotEnoughSpace => DbusErrorEnum::NOT_ENOUGH_SPACE,
+            ErrorEnum::PolicyViolation => DbusErrorEnum::POLICY_VIOLATION,
         },
         StratisError::Io(_) => DbusErrorEnum::IO_ERROR,
         StratisError::Nix(_) => DbusErrorEnum::NIX_ERROR,
@@ -185,6 +185,8 @@ pub trait Pool: Debug {
 
 pub trait Engine: Debug {
     /// Create a Stratis pool.
+    /// If key_desc is given, the data on the blockdevs is encrypted with the
+    /// key it describes, which must already be in the kernel keyring.
     /// Returns the UUID of the newly created pool.
     /// Returns an error if the redundancy code does not correspond to a
     /// supported redundancy.
@@ -194,6 +196,7 @@ pub trait Engine: Debug {
         blockdev_paths: &[&Path],
         redundancy: Option<u16>,
         force: bool,
+        key_desc: Option<&str>,
     ) -> StratisResult<PoolUuid>;
 
     /// Evaluate a device node & devicemapper::Device to see if it's a valid
@@ -238,6 +241,10 @@ pub trait Engine: Debug {
 
     /// Notify the engine that an event has occurred on the Eventable.
     fn evented(&mut self) -> StratisResult<()>;
+
+    /// Quiesce the devices of every pool before the daemon exits.
+    /// Afterward, no pool may be changed.
+    fn shutdown(&mut self) -> StratisResult<()>;
 }
 
 /// Allows an Engine to include a fd in the event loop. See
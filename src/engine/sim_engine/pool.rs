@@ -311,7 +311,7 @@ mod tests {
     fn rename_empty() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.rename_filesystem(pool_name, Uuid::new_v4(), "new_name") {
@@ -326,7 +326,7 @@ mod tests {
     fn rename_happens() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         let infos = pool.create_filesystems(uuid, pool_name, &[("old_name", None)])
             .unwrap();
@@ -345,7 +345,7 @@ mod tests {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         let results =
             pool.create_filesystems(uuid, pool_name, &[(old_name, None), (new_name, None)])
@@ -365,7 +365,7 @@ mod tests {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.rename_filesystem(pool_name, Uuid::new_v4(), new_name) {
@@ -380,7 +380,7 @@ mod tests {
     fn destroy_fs_empty() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(match pool.destroy_filesystems(pool_name, &[]) {
             Ok(names) => names.is_empty(),
@@ -393,7 +393,7 @@ mod tests {
     fn destroy_fs_some() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             pool.destroy_filesystems(pool_name, &[Uuid::new_v4()])
@@ -406,7 +406,7 @@ mod tests {
     fn destroy_fs_any() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         let fs_results = pool.create_filesystems(uuid, pool_name, &[("fs_name", None)])
             .unwrap();
@@ -424,7 +424,7 @@ mod tests {
     fn create_fs_none() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(match pool.create_filesystems(uuid, pool_name, &[]) {
             Ok(names) => names.is_empty(),
@@ -437,7 +437,7 @@ mod tests {
     fn create_fs_some() {
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.create_filesystems(uuid, pool_name, &[("name", None)]) {
@@ -453,7 +453,7 @@ mod tests {
         let fs_name = "fs_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         pool.create_filesystems(uuid, pool_name, &[(fs_name, None)])
             .unwrap();
@@ -471,7 +471,7 @@ mod tests {
         let fs_name = "fs_name";
         let mut engine = SimEngine::default();
         let pool_name = "pool_name";
-        let uuid = engine.create_pool(pool_name, &[], None, false).unwrap();
+        let uuid = engine.create_pool(pool_name, &[], None, false, None).unwrap();
         let pool = engine.get_mut_pool(uuid).unwrap().1;
         assert!(
             match pool.create_filesystems(uuid, pool_name, &[(fs_name, None), (fs_name, None)]) {
@@ -485,7 +485,7 @@ mod tests {
     /// Adding a list of devices to an empty pool should yield list.
     fn add_device_empty() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("pool_name", &[], None, false).unwrap();
+        let uuid = engine.create_pool("pool_name", &[], None, false, None).unwrap();
         let (pool_name, pool) = engine.get_mut_pool(uuid).unwrap();
         let devices = [Path::new("/s/a"), Path::new("/s/b")];
         assert!(
@@ -22,7 +22,7 @@ use super::super::dmnames::{format_backstore_ids, CacheRole};
 use super::super::serde_structs::{BackstoreSave, Recordable};
 
 use super::blockdev::StratBlockDev;
-use super::blockdevmgr::{map_to_dm, BlockDevMgr};
+use super::blockdevmgr::{map_to_dm, BlockDevMgr, BlockDevMgrConfig, ForceToken};
 use super::cache_tier::CacheTier;
 use super::data_tier::DataTier;
 use super::metadata::MIN_MDA_SECTORS;
@@ -111,7 +111,14 @@ impl Backstore {
         next: Sectors,
     ) -> StratisResult<Backstore> {
         let (datadevs, cachedevs) = get_blockdevs(pool_uuid, backstore_save, devnodes)?;
-        let block_mgr = BlockDevMgr::new(datadevs, last_update_time);
+        let mut block_mgr = BlockDevMgr::new(datadevs, last_update_time);
+        block_mgr.load_last_metadata();
+        if let Some(chunk) = backstore_save.alloc_chunk {
+            block_mgr.set_alloc_chunk(chunk)?;
+        }
+        if let Some(min_devices) = backstore_save.min_devices {
+            block_mgr.set_min_devices(min_devices);
+        }
         let data_tier = DataTier::setup(block_mgr, &backstore_save.data_segments)?;
         let (dm_name, dm_uuid) = format_backstore_ids(pool_uuid, CacheRole::OriginSub);
         let origin = LinearDev::setup(
@@ -161,9 +168,21 @@ impl Backstore {
         pool_uuid: PoolUuid,
         paths: &[&Path],
         mda_size: Sectors,
-        force: bool,
+        force: Option<ForceToken>,
+        key_desc: Option<&str>,
     ) -> StratisResult<Backstore> {
-        let data_tier = DataTier::new(BlockDevMgr::initialize(pool_uuid, paths, mda_size, force)?);
+        let block_mgr = match key_desc {
+            Some(key_desc) => BlockDevMgr::initialize_encrypted(
+                pool_uuid,
+                paths,
+                mda_size,
+                force,
+                key_desc,
+                BlockDevMgrConfig::default(),
+            )?,
+            None => BlockDevMgr::initialize(pool_uuid, paths, mda_size, force)?,
+        };
+        let data_tier = DataTier::new(block_mgr);
 
         Ok(Backstore {
             data_tier,
@@ -186,7 +205,7 @@ impl Backstore {
         &mut self,
         pool_uuid: PoolUuid,
         paths: &[&Path],
-        force: bool,
+        force: Option<ForceToken>,
     ) -> StratisResult<Vec<DevUuid>> {
         match self.cache_tier {
             Some(ref mut cache_tier) => {
@@ -245,9 +264,11 @@ impl Backstore {
         &mut self,
         pool_uuid: PoolUuid,
         paths: &[&Path],
-        force: bool,
+        force: Option<ForceToken>,
     ) -> StratisResult<Vec<DevUuid>> {
-        self.data_tier.add(pool_uuid, paths, force)
+        self.data_tier
+            .add(pool_uuid, paths, force)
+            .map(|(uuids, _)| uuids)
     }
 
     /// Add the given paths to self. Return UUIDs of the new blockdevs
@@ -258,7 +279,7 @@ impl Backstore {
         pool_uuid: PoolUuid,
         paths: &[&Path],
         tier: BlockDevTier,
-        force: bool,
+        force: Option<ForceToken>,
     ) -> StratisResult<Vec<DevUuid>> {
         match tier {
             BlockDevTier::Cache => self.add_cachedevs(pool_uuid, paths, force),
@@ -319,7 +340,8 @@ impl Backstore {
         let total_required = sizes.iter().cloned().sum();
         let available = self.available();
         if available < total_required {
-            if self.data_tier.alloc(total_required - available) {
+            let needed = total_required - available;
+            if self.data_tier.alloc(needed).grown() >= needed {
                 self.extend_cap_device(pool_uuid)?;
             } else {
                 return Ok(None);
@@ -377,7 +399,8 @@ impl Backstore {
         if available < internal_request {
             let mut allocated = false;
             while !allocated && internal_request != Sectors(0) {
-                allocated = self.data_tier.alloc(internal_request - available);
+                let needed = internal_request - available;
+                allocated = self.data_tier.alloc(needed).grown() >= needed;
                 let temp = internal_request / 2usize;
                 internal_request = (temp / modulus) * modulus;
             }
@@ -477,7 +500,18 @@ impl Backstore {
         self.data_tier.destroy()
     }
 
-    /// Teardown the DM devices in the backstore.
+    /// Quiesce the blockdevs in both tiers before a clean shutdown. Both
+    /// tiers are shut down even if shutting down the data tier fails.
+    pub fn shutdown(&mut self) -> StratisResult<()> {
+        let result = self.data_tier.block_mgr.shutdown();
+        if let Some(ref mut cache_tier) = self.cache_tier {
+            cache_tier.block_mgr.shutdown()?;
+        }
+        result
+    }
+
+    /// Teardown the DM devices in the backstore, including the dm-crypt
+    /// devices of encrypted blockdevs, which lie beneath the others.
     #[cfg(test)]
     pub fn teardown(&mut self) -> StratisResult<()> {
         match self.cache {
@@ -487,7 +521,11 @@ impl Backstore {
             } else {
                 Ok(())
             },
-        }.map_err(|e| e.into())
+        }?;
+        if let Some(ref mut cache_tier) = self.cache_tier {
+            cache_tier.teardown()?;
+        }
+        self.data_tier.teardown()
     }
 
     /// Return the device that this tier is currently using.
@@ -536,6 +574,12 @@ impl Backstore {
         self.data_tier.save_state(metadata)
     }
 
+    /// The number of devices in the data tier that could be lost without
+    /// losing the current metadata.
+    pub fn datatier_metadata_redundancy(&self) -> usize {
+        self.data_tier.block_mgr.metadata_redundancy()
+    }
+
     /// Set user info field on the specified blockdev.
     /// May return an error if there is no blockdev for the given UUID.
     pub fn set_blockdev_user_info(
@@ -557,13 +601,19 @@ impl Backstore {
 
 impl Recordable<BackstoreSave> for Backstore {
     fn record(&self) -> BackstoreSave {
-        BackstoreSave {
+        let mut save = BackstoreSave {
             cache_devs: self.cache_tier.as_ref().map(|c| c.block_mgr.record()),
             cache_segments: self.cache_tier.as_ref().map(|c| c.cache_segments.record()),
             data_devs: self.data_tier.block_mgr.record(),
-            data_segments: self.data_tier.segments.record(),
+            data_segments: self.data_tier.record_segments(),
             meta_segments: self.cache_tier.as_ref().map(|c| c.meta_segments.record()),
-        }
+            alloc_chunk: Some(self.data_tier.block_mgr.alloc_chunk())
+                .filter(|&chunk| chunk != Sectors(1)),
+            min_devices: Some(self.data_tier.block_mgr.min_devices())
+                .filter(|&min_devices| min_devices != 1),
+        };
+        save.raise_schema_versions();
+        save
     }
 }
 
@@ -621,7 +671,7 @@ mod tests {
 
         let pool_uuid = Uuid::new_v4();
         let mut backstore =
-            Backstore::initialize(pool_uuid, initdatapaths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, initdatapaths, MIN_MDA_SECTORS, None, None).unwrap();
 
         invariant(&backstore);
 
@@ -629,7 +679,7 @@ mod tests {
         backstore.alloc(pool_uuid, &[Sectors(1)]).unwrap();
 
         let cache_uuids = backstore
-            .add_blockdevs(pool_uuid, initcachepaths, BlockDevTier::Cache, false)
+            .add_blockdevs(pool_uuid, initcachepaths, BlockDevTier::Cache, None)
             .unwrap();
 
         invariant(&backstore);
@@ -654,13 +704,13 @@ mod tests {
         }
 
         let data_uuids = backstore
-            .add_blockdevs(pool_uuid, datadevpaths, BlockDevTier::Data, false)
+            .add_blockdevs(pool_uuid, datadevpaths, BlockDevTier::Data, None)
             .unwrap();
         invariant(&backstore);
         assert_eq!(data_uuids.len(), datadevpaths.len());
 
         let cache_uuids = backstore
-            .add_blockdevs(pool_uuid, cachedevpaths, BlockDevTier::Cache, false)
+            .add_blockdevs(pool_uuid, cachedevpaths, BlockDevTier::Cache, None)
             .unwrap();
         invariant(&backstore);
         assert_eq!(cache_uuids.len(), cachedevpaths.len());
@@ -718,7 +768,7 @@ mod tests {
 
         let pool_uuid = Uuid::new_v4();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
 
         assert!(
             backstore
@@ -781,7 +831,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
 
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths1, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths1, MIN_MDA_SECTORS, None, None).unwrap();
         invariant(&backstore);
 
         // Allocate space from the backstore so that the cap device is made.
@@ -790,7 +840,7 @@ mod tests {
         let old_device = backstore.device();
 
         backstore
-            .add_blockdevs(pool_uuid, paths2, BlockDevTier::Cache, false)
+            .add_blockdevs(pool_uuid, paths2, BlockDevTier::Cache, None)
             .unwrap();
         invariant(&backstore);
 
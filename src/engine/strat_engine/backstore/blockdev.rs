@@ -5,20 +5,25 @@
 // Code to handle a single block device.
 
 use std::fs::OpenOptions;
+#[cfg(test)]
+use std::io::Seek;
 use std::path::PathBuf;
 
 use chrono::{DateTime, TimeZone, Utc};
 
 use devicemapper::{Device, Sectors};
 
-use stratis::StratisResult;
+use stratis::{ErrorEnum, StratisError, StratisResult};
 
 use super::super::super::engine::BlockDev;
 use super::super::super::event::{get_engine_listener_list, EngineEvent};
 use super::super::super::types::{BlockDevState, DevUuid, MaybeDbusPath, PoolUuid};
 
-use super::super::serde_structs::{BlockDevSave, Recordable};
+#[cfg(test)]
+use super::super::device::SyncAll;
+use super::super::serde_structs::{BlockDevSave, Recordable, BLOCKDEV_SAVE_SCHEMA_VERSION};
 
+use super::crypt::{setup_crypt, teardown_crypt};
 use super::metadata::BDA;
 use super::range_alloc::RangeAllocator;
 
@@ -30,6 +35,13 @@ pub struct StratBlockDev {
     used: RangeAllocator,
     user_info: Option<String>,
     hardware_info: Option<String>,
+    /// The description of the key in the kernel keyring with which the
+    /// data on this device is encrypted, if it is encrypted.
+    key_description: Option<String>,
+    /// The dm-crypt device through which the data on this device is
+    /// accessed, if it is encrypted and has been unlocked. Not recorded in
+    /// the metadata.
+    crypt_device: Option<Device>,
     dbus_path: MaybeDbusPath,
 }
 
@@ -56,7 +68,7 @@ impl StratBlockDev {
         upper_segments: &[(Sectors, Sectors)],
         user_info: Option<String>,
         hardware_info: Option<String>,
-    ) -> StratisResult<StratBlockDev> {
+        ) -> StratisResult<StratBlockDev> {
         let mut segments = vec![(Sectors(0), bda.size())];
         segments.extend(upper_segments);
         let allocator = RangeAllocator::new(bda.dev_size(), &segments)?;
@@ -68,36 +80,168 @@ impl StratBlockDev {
             used: allocator,
             user_info,
             hardware_info,
+            key_description: None,
+            crypt_device: None,
             dbus_path: MaybeDbusPath(None),
         })
     }
 
+    /// Make a blockdev of the given size in the pool with UUID pool_uuid,
+    /// writing its BDA to f, which stands for the device at devnode, and
+    /// saving some metadata to it at each of the given times. The device
+    /// number is made from index, so that the blockdevs made with distinct
+    /// indices are distinct devices.
+    #[cfg(test)]
+    pub fn initialize_for_test<F>(
+        f: &mut F,
+        pool_uuid: PoolUuid,
+        dev_uuid: DevUuid,
+        index: u32,
+        devnode: PathBuf,
+        size: Sectors,
+        saves: &[DateTime<Utc>],
+    ) -> StratBlockDev
+    where
+        F: Seek + SyncAll,
+    {
+        use super::metadata::MIN_MDA_SECTORS;
+
+        let mut bda = BDA::initialize(
+            f,
+            pool_uuid,
+            dev_uuid,
+            MIN_MDA_SECTORS,
+            size,
+            Utc::now().timestamp() as u64,
+        ).expect("the BDA fits in the device");
+        for time in saves {
+            bda.save_state(time, &[1u8; 16], f)
+                .expect("the metadata fits in the MDA");
+        }
+        StratBlockDev::new(
+            Device {
+                major: 8,
+                minor: 16 * index,
+            },
+            devnode,
+            bda,
+            &[],
+            None,
+            None,
+        ).expect("a new allocator has room for the BDA")
+    }
+
     /// Returns the blockdev's Device
     pub fn device(&self) -> &Device {
         &self.dev
     }
 
+    /// The Device through which the data on this blockdev is accessed,
+    /// which is the dm-crypt device if the blockdev is encrypted, and the
+    /// blockdev's own Device otherwise. Segments allocated from the blockdev
+    /// are mapped from this device.
+    pub fn data_device(&self) -> Device {
+        self.crypt_device.unwrap_or(self.dev)
+    }
+
+    /// The description of the key with which the data on this device is
+    /// encrypted, if it is encrypted.
+    pub fn key_description(&self) -> Option<&String> {
+        self.key_description.as_ref()
+    }
+
+    /// Set up the dm-crypt device through which the data on this device is
+    /// accessed, encrypted with the key described by key_desc, which must
+    /// already be in the kernel keyring. The key description is recorded
+    /// in the metadata when it is next saved. Returns an error if the data
+    /// on this device is already encrypted with a different key.
+    pub fn unlock(&mut self, key_desc: &str) -> StratisResult<()> {
+        if let Some(ref recorded) = self.key_description {
+            if recorded != key_desc {
+                let err_msg = format!(
+                    "device {} is encrypted with key \"{}\", not \"{}\"",
+                    self.devnode.display(),
+                    recorded,
+                    key_desc
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        let crypt_device = setup_crypt(
+            self.bda.pool_uuid(),
+            self.uuid(),
+            self.dev,
+            self.bda.dev_size(),
+            key_desc,
+        )?;
+        self.key_description = Some(key_desc.to_owned());
+        self.crypt_device = Some(crypt_device);
+        Ok(())
+    }
+
+    /// Tear down the dm-crypt device of this device, if it was unlocked.
+    pub fn lock(&mut self) -> StratisResult<()> {
+        if self.crypt_device.is_some() {
+            teardown_crypt(self.bda.pool_uuid(), self.uuid())?;
+            self.crypt_device = None;
+        }
+        Ok(())
+    }
+
     pub fn wipe_metadata(&self) -> StratisResult<()> {
         let mut f = OpenOptions::new().write(true).open(&self.devnode)?;
         BDA::wipe(&mut f)
     }
 
+    /// Save the metadata to the device, with the given time. The metadata
+    /// has been synced to the device if this method returns Ok.
     pub fn save_state(&mut self, time: &DateTime<Utc>, metadata: &[u8]) -> StratisResult<()> {
         let mut f = OpenOptions::new().write(true).open(&self.devnode)?;
         self.bda.save_state(time, metadata, &mut f)
     }
 
+    /// Flush to the device any writes to it that are cached by the kernel.
+    pub fn sync(&self) -> StratisResult<()> {
+        OpenOptions::new().read(true).open(&self.devnode)?.sync_all()?;
+        Ok(())
+    }
+
     /// The device's UUID.
     pub fn uuid(&self) -> DevUuid {
         self.bda.dev_uuid()
     }
 
     /// The device's pool's UUID.
-    #[allow(dead_code)]
     pub fn pool_uuid(&self) -> PoolUuid {
         self.bda.pool_uuid()
     }
 
+    /// Read back the metadata most recently saved to this device, if any.
+    /// Returns an error if the metadata on the device does not match the
+    /// checksum recorded when it was saved.
+    pub fn load_state(&self) -> StratisResult<Option<Vec<u8>>> {
+        let mut f = OpenOptions::new().read(true).open(&self.devnode)?;
+        self.bda.load_state(&mut f)
+    }
+
+    /// Write garbage over the header of the MDA region of this device with
+    /// the given index, as a torn write might leave it, so that the region
+    /// is found to be invalid when it is next read.
+    #[cfg(test)]
+    pub fn corrupt_mda_region(&self, index: usize) -> StratisResult<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let offset = self.bda.mda_region_offset(index).ok_or_else(|| {
+            let err_msg = format!("device {} has no MDA region {}", self.uuid(), index);
+            StratisError::Engine(ErrorEnum::Invalid, err_msg)
+        })?;
+        let mut f = OpenOptions::new().write(true).open(&self.devnode)?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(&[0xa5; 512])?;
+        f.sync_all()?;
+        Ok(())
+    }
+
     /// Last time metadata was written to this device.
     #[allow(dead_code)]
     pub fn last_update_time(&self) -> Option<&DateTime<Utc>> {
@@ -107,7 +251,7 @@ impl StratBlockDev {
     /// Find some sector ranges that could be allocated. If more
     /// sectors are needed than our capacity, return partial results.
     /// If all sectors are desired, use available() method to get all.
-    pub fn request_space(&mut self, size: Sectors) -> (Sectors, Vec<(Sectors, Sectors)>) {
+    pub(super) fn request_space(&mut self, size: Sectors) -> (Sectors, Vec<(Sectors, Sectors)>) {
         let prev_state = self.state();
         let result = self.used.request(size);
         if result.0 > Sectors(0) && prev_state != BlockDevState::InUse {
@@ -119,6 +263,33 @@ impl StratBlockDev {
         result
     }
 
+    /// Return the given (offset, length) ranges to the pool of space that
+    /// may be allocated.
+    /// Returns an error if any range overlaps the BDA or is not currently
+    /// allocated, in which case nothing is released.
+    pub(super) fn release_space(&mut self, ranges: &[(Sectors, Sectors)]) -> StratisResult<()> {
+        let bda_size = self.bda.size();
+        if let Some(&(start, length)) = ranges.iter().find(|&&(start, _)| start < bda_size) {
+            let err_msg = format!(
+                "range ({}, {}) on device {} overlaps the BDA",
+                start,
+                length,
+                self.uuid()
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let prev_state = self.state();
+        self.used.release(ranges)?;
+        if prev_state == BlockDevState::InUse && self.state() != BlockDevState::InUse {
+            get_engine_listener_list().notify(&EngineEvent::BlockdevStateChanged {
+                dbus_path: self.get_dbus_path(),
+                state: BlockDevState::NotInUse,
+            });
+        }
+        Ok(())
+    }
+
     // ALL SIZE METHODS
     /// The actual size of the device now.
     pub fn current_capacity(&self) -> Sectors {
@@ -132,19 +303,62 @@ impl StratBlockDev {
         self.bda.size()
     }
 
+    /// The number of Sectors on this device occupied by the MDA regions.
+    pub fn mda_size(&self) -> Sectors {
+        self.bda.mda_size()
+    }
+
     /// The number of Sectors on this device not allocated for any purpose.
     /// self.current_capacity() - self.metadata_size() >= self.available()
     pub fn available(&self) -> Sectors {
         self.used.available()
     }
 
+    /// The length of the largest contiguous run of sectors on this device
+    /// not allocated for any purpose.
+    pub fn largest_available(&self) -> Sectors {
+        self.used.largest_available()
+    }
+
     /// The maximum size of variable length metadata that can be accommodated.
     /// self.max_metadata_size() < self.metadata_size()
     pub fn max_metadata_size(&self) -> Sectors {
         self.bda.max_data_size()
     }
 
+    /// Verify that the allocator for this blockdev is consistent with
+    /// itself and with the BDA. The allocator must cover exactly the
+    /// device size recorded in the BDA and the region occupied by the BDA
+    /// must be marked as used.
+    pub fn check_invariants(&self) -> StratisResult<()> {
+        self.used.check_invariants()?;
+
+        if self.used.capacity() != self.bda.dev_size() {
+            let err_msg = format!(
+                "allocator for device {} has capacity {}, but recorded device size is {}",
+                self.uuid(),
+                self.used.capacity(),
+                self.bda.dev_size()
+            );
+            return Err(StratisError::Engine(ErrorEnum::Error, err_msg));
+        }
+
+        match self.used.used_extents().first() {
+            Some(&(start, length)) if start == Sectors(0) && length >= self.bda.size() => Ok(()),
+            _ => {
+                let err_msg = format!(
+                    "allocator for device {} does not reserve the {} sectors of its BDA",
+                    self.uuid(),
+                    self.bda.size()
+                );
+                Err(StratisError::Engine(ErrorEnum::Error, err_msg))
+            }
+        }
+    }
+
     /// Set the user info on this blockdev.
+    /// The user info is a free-form, operator chosen label, e.g.,
+    /// "shelf2-bay7", and is recorded in the pool metadata.
     /// The user_info may be None, which unsets user info.
     /// Returns true if the user info was changed, otherwise false.
     pub fn set_user_info(&mut self, user_info: Option<&str>) -> bool {
@@ -198,10 +412,14 @@ impl BlockDev for StratBlockDev {
 
 impl Recordable<BlockDevSave> for StratBlockDev {
     fn record(&self) -> BlockDevSave {
-        BlockDevSave {
+        let mut save = BlockDevSave {
+            schema_version: BLOCKDEV_SAVE_SCHEMA_VERSION,
             uuid: self.uuid(),
             user_info: self.user_info.clone(),
             hardware_info: self.hardware_info.clone(),
-        }
+            key_description: self.key_description.clone(),
+        };
+        save.schema_version = save.required_schema_version();
+        save
     }
 }
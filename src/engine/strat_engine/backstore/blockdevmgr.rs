@@ -4,13 +4,19 @@
 
 // Code to handle a collection of block devices.
 
+use std::cmp::{min, Reverse};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 
 use chrono::{DateTime, Duration, Utc};
+use nix::libc::{EBUSY, ENODEV, ENXIO, O_EXCL};
 use rand::{seq, thread_rng};
+use serde_json;
 use uuid::Uuid;
 
 use devicemapper::{
@@ -21,17 +27,53 @@ use stratis::{ErrorEnum, StratisError, StratisResult};
 
 use super::super::super::types::{DevUuid, PoolUuid};
 
-use super::super::serde_structs::{BlockDevSave, Recordable};
+use super::super::serde_structs::{BlockDevSave, PoolSave, Recordable};
 
 use super::blockdev::StratBlockDev;
 use super::cleanup::wipe_blockdevs;
+use super::crypt::validate_key_description;
 use super::device::{blkdev_size, identify, resolve_devices, DevOwnership};
-use super::metadata::{validate_mda_size, BDA, MIN_MDA_SECTORS};
-use super::util::hw_lookup;
+use super::metadata::{bda_size, max_metadata_len, validate_mda_size, BDA, MIN_MDA_SECTORS};
+use super::util::{hw_lookup, mounted_devices, partition_sibling_in_use, thin_backed_lookup};
 
 const MIN_DEV_SIZE: Bytes = Bytes(IEC::Gi);
+/// The least space that must remain for data on a device once its Stratis
+/// metadata has been written.
+const MIN_DATA_SIZE: Bytes = Bytes(512 * IEC::Mi);
 const MAX_NUM_TO_WRITE: usize = 10;
 
+/// The default bound on the number of metadata writes to blockdevs that
+/// may be in progress at once.
+const DEFAULT_MAX_CONCURRENT_WRITES: usize = 8;
+
+/// Prefix a log message about a device with the identifiers of the device:
+/// the UUID of the pool it belongs to or is being added to, its Stratis
+/// UUID, once it has one, and its devnode. Every message about a device
+/// logged from this module is made by means of this function, so that the
+/// history of a device can be found by searching the log for any of them.
+fn with_dev_context(
+    pool_uuid: PoolUuid,
+    dev_uuid: Option<DevUuid>,
+    devnode: &Path,
+    msg: fmt::Arguments,
+) -> String {
+    match dev_uuid {
+        Some(dev_uuid) => format!(
+            "pool {} device {} ({}): {}",
+            pool_uuid.simple(),
+            dev_uuid.simple(),
+            devnode.display(),
+            msg
+        ),
+        None => format!(
+            "pool {} device ({}): {}",
+            pool_uuid.simple(),
+            devnode.display(),
+            msg
+        ),
+    }
+}
+
 /// struct to represent a continuous set of sectors on a disk
 #[derive(Debug, Clone)]
 pub struct Segment {
@@ -154,10 +196,49 @@ pub fn map_to_dm(bsegs: &[BlkDevSegment]) -> Vec<TargetLine<LinearDevTargetParam
     table
 }
 
+/// Options for a BlockDevMgr. The defaults are the options of a
+/// BlockDevMgr made by new() or initialize().
+#[derive(Debug, Clone)]
+pub struct BlockDevMgrConfig {
+    max_concurrent_writes: usize,
+    alloc_chunk: Sectors,
+    loss_policy: DeviceLossPolicy,
+    min_devices: usize,
+}
+
+impl Default for BlockDevMgrConfig {
+    fn default() -> BlockDevMgrConfig {
+        BlockDevMgrConfig {
+            max_concurrent_writes: DEFAULT_MAX_CONCURRENT_WRITES,
+            alloc_chunk: Sectors(1),
+            loss_policy: DeviceLossPolicy::Abort,
+            min_devices: 1,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct BlockDevMgr {
     block_devs: Vec<StratBlockDev>,
     last_update_time: Option<DateTime<Utc>>,
+    /// The metadata most recently saved, and the time it was saved with.
+    last_metadata: Option<(DateTime<Utc>, Vec<u8>)>,
+    /// The most metadata writes to blockdevs that may be in progress at
+    /// once when saving metadata.
+    max_concurrent_writes: usize,
+    /// The unit in which space is allocated by allocations that round
+    /// their requests with round_to_chunk().
+    alloc_chunk: Sectors,
+    /// The total of the space available on the blockdevs, maintained by
+    /// every method that adds blockdevs or allocates or releases space, so
+    /// that it need not be summed over all the blockdevs on each request.
+    avail_space: Sectors,
+    /// Whether shutdown() has been called. If so, no operation that would
+    /// change the blockdevs or their allocations is permitted.
+    shut_down: bool,
+    /// The fewest blockdevs with which initialize_with_config() may make a
+    /// pool. Not recorded in the metadata.
+    min_devices: usize,
 }
 
 impl BlockDevMgr {
@@ -166,9 +247,100 @@ impl BlockDevMgr {
         block_devs: Vec<StratBlockDev>,
         last_update_time: Option<DateTime<Utc>>,
     ) -> BlockDevMgr {
-        BlockDevMgr {
+        BlockDevMgr::new_with_config(block_devs, last_update_time, BlockDevMgrConfig::default())
+            .expect("the default configuration is valid")
+    }
+
+    /// Make a struct that represents an existing BlockDevMgr with the
+    /// given options. Returns an error if the options are inconsistent.
+    pub fn new_with_config(
+        block_devs: Vec<StratBlockDev>,
+        last_update_time: Option<DateTime<Utc>>,
+        config: BlockDevMgrConfig,
+    ) -> StratisResult<BlockDevMgr> {
+        let mut mgr = BlockDevMgr {
             block_devs,
             last_update_time,
+            last_metadata: None,
+            max_concurrent_writes: DEFAULT_MAX_CONCURRENT_WRITES,
+            alloc_chunk: Sectors(1),
+            avail_space: Sectors(0),
+            shut_down: false,
+            min_devices: config.min_devices,
+        };
+        mgr.avail_space = mgr.sum_avail_space();
+        mgr.set_max_concurrent_writes(config.max_concurrent_writes)?;
+        mgr.set_alloc_chunk(config.alloc_chunk)?;
+        Ok(mgr)
+    }
+
+    /// Make a BlockDevMgr with a blockdev of each of the given sizes, none
+    /// of which is backed by any device. Allocating and releasing space,
+    /// which involve no IO, may be tested without root privileges or
+    /// loop devices; any operation that writes to a blockdev fails.
+    #[cfg(test)]
+    pub fn memory_backed(pool_uuid: PoolUuid, sizes: &[Sectors]) -> BlockDevMgr {
+        use std::io::Cursor;
+        use std::path::PathBuf;
+
+        let block_devs = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                StratBlockDev::initialize_for_test(
+                    &mut Cursor::new(vec![]),
+                    pool_uuid,
+                    Uuid::new_v4(),
+                    i as u32,
+                    PathBuf::from(format!("/dev/memory{}", i)),
+                    size,
+                    &[],
+                )
+            })
+            .collect();
+        BlockDevMgr::new(block_devs, None)
+    }
+
+    /// Make a BlockDevMgr with a blockdev of each of the given sizes, each
+    /// backed by a file in dir, so that the operations that write to and
+    /// read back from the blockdevs, but that do not require a block device,
+    /// may be tested without root privileges or loop devices.
+    #[cfg(test)]
+    pub fn file_backed(pool_uuid: PoolUuid, dir: &Path, sizes: &[Sectors]) -> BlockDevMgr {
+        let block_devs = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                let devnode = dir.join(format!("dev{}", i));
+                let mut f = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&devnode)
+                    .expect("the directory is writable");
+                f.set_len(*size.bytes()).expect("the directory is writable");
+                StratBlockDev::initialize_for_test(
+                    &mut f,
+                    pool_uuid,
+                    Uuid::new_v4(),
+                    i as u32,
+                    devnode,
+                    size,
+                    &[],
+                )
+            })
+            .collect();
+        BlockDevMgr::new(block_devs, None)
+    }
+
+    /// Return an error if this BlockDevMgr has been shut down.
+    fn check_writable(&self, action: &str) -> StratisResult<()> {
+        if self.shut_down {
+            let err_msg = format!("can not {}, blockdevs have been shut down", action);
+            Err(StratisError::Engine(ErrorEnum::Invalid, err_msg))
+        } else {
+            Ok(())
         }
     }
 
@@ -177,95 +349,403 @@ impl BlockDevMgr {
         pool_uuid: PoolUuid,
         paths: &[&Path],
         mda_size: Sectors,
-        force: bool,
+        force: Option<ForceToken>,
     ) -> StratisResult<BlockDevMgr> {
-        let devices = resolve_devices(paths)?;
-        Ok(BlockDevMgr::new(
-            initialize(pool_uuid, devices, mda_size, force, &HashSet::new())?,
-            None,
-        ))
+        BlockDevMgr::initialize_with_config(
+            pool_uuid,
+            paths,
+            mda_size,
+            force,
+            BlockDevMgrConfig::default(),
+        )
+    }
+
+    /// Initialize a new StratBlockDevMgr with specified pool and devices,
+    /// and with the given options. The options are checked before any
+    /// device is opened.
+    pub fn initialize_with_config(
+        pool_uuid: PoolUuid,
+        paths: &[&Path],
+        mda_size: Sectors,
+        force: Option<ForceToken>,
+        config: BlockDevMgrConfig,
+    ) -> StratisResult<BlockDevMgr> {
+        // Check the size first to avoid opening any device if it is invalid.
+        validate_mda_size(mda_size)?;
+        let loss_policy = config.loss_policy;
+        let mut mgr = BlockDevMgr::new_with_config(vec![], None, config)?;
+        mgr.check_writable("initialize blockdevs")?;
+        // No device is opened if there are too few paths. If there are none
+        // at all, resolving them reports that instead.
+        if !paths.is_empty() {
+            mgr.check_min_devices(paths.len())?;
+        }
+        let admitted = DeviceSet::resolve(paths)?.filter(
+            pool_uuid,
+            mda_size,
+            force,
+            &HashSet::new(),
+        )?;
+        mgr.check_min_devices(admitted.devices.len())?;
+        let bds = admitted.init(pool_uuid, mda_size, loss_policy)?;
+        // Some devices may have been lost during initialization.
+        if let Err(err) = mgr.check_min_devices(bds.len()) {
+            // TODO: check the return value and update state machine on failure
+            let _ = wipe_blockdevs(&bds);
+            return Err(err);
+        }
+        mgr.block_devs = bds;
+        mgr.avail_space = mgr.sum_avail_space();
+        Ok(mgr)
+    }
+
+    /// Initialize a new StratBlockDevMgr with specified pool and devices,
+    /// and encrypt the data on every device with the key described by
+    /// key_desc, which must already be in the kernel keyring. The Stratis
+    /// metadata itself is not encrypted, so that the devices can be
+    /// identified before they are unlocked, but it records the key
+    /// description, so that the devices are unlocked when the pool is set
+    /// up. Segments allocated are mapped from the unlocked devices.
+    /// The devices are initialized with the given options, as by
+    /// initialize_with_config().
+    pub fn initialize_encrypted(
+        pool_uuid: PoolUuid,
+        paths: &[&Path],
+        mda_size: Sectors,
+        force: Option<ForceToken>,
+        key_desc: &str,
+        config: BlockDevMgrConfig,
+    ) -> StratisResult<BlockDevMgr> {
+        validate_key_description(key_desc)?;
+        let mut mgr =
+            BlockDevMgr::initialize_with_config(pool_uuid, paths, mda_size, force, config)?;
+        unlock_new(&mut mgr.block_devs, key_desc)?;
+        Ok(mgr)
+    }
+
+    /// The description of the key with which the data on the blockdevs is
+    /// encrypted, if it is encrypted. Blockdevs added later are encrypted
+    /// with the same key.
+    pub fn key_description(&self) -> Option<&String> {
+        self.block_devs
+            .iter()
+            .filter_map(|bd| bd.key_description())
+            .next()
     }
 
-    /// Get a function that maps UUIDs to Devices.
+    /// Get a function that maps UUIDs to the Devices through which the data
+    /// on the blockdevs is accessed.
     pub fn uuid_to_devno(&self) -> Box<Fn(DevUuid) -> Option<Device>> {
         let uuid_map: HashMap<DevUuid, Device> = self.block_devs
             .iter()
-            .map(|bd| (bd.uuid(), *bd.device()))
+            .map(|bd| (bd.uuid(), bd.data_device()))
             .collect();
 
         Box::new(move |uuid: DevUuid| -> Option<Device> { uuid_map.get(&uuid).cloned() })
     }
 
     /// Add paths to self.
-    /// Return the uuids of all blockdevs corresponding to paths that were
-    /// added.
+    /// The new blockdevs get MDA regions as large as the largest of any
+    /// existing blockdev, so that they can hold any metadata the others can.
+    /// Paths that refer to blockdevs already in self are not probed again,
+    /// so adding them again has no effect.
+    /// Devices are overwritten only as far as force permits.
+    /// Return the uuids of the blockdevs that were added.
     pub fn add(
         &mut self,
         pool_uuid: PoolUuid,
         paths: &[&Path],
-        force: bool,
+        force: Option<ForceToken>,
     ) -> StratisResult<Vec<DevUuid>> {
-        let devices = resolve_devices(paths)?;
+        self.check_writable("add blockdevs")?;
+        let mda_size = self.mda_size();
+        self.check_metadata_fits(mda_size)?;
         let current_uuids = self.block_devs.iter().map(|bd| bd.uuid()).collect();
-        let bds = initialize(pool_uuid, devices, MIN_MDA_SECTORS, force, &current_uuids)?;
+        let mut device_set = DeviceSet::resolve(paths)?;
+        device_set.remove_known(|dev| self.get_blockdev_by_device(dev).is_some());
+        let mut bds = device_set
+            .filter(pool_uuid, mda_size, force, &current_uuids)?
+            .init(pool_uuid, mda_size, DeviceLossPolicy::Abort)?;
+        self.encrypt_new(&mut bds)?;
         let bdev_uuids = bds.iter().map(|bd| bd.uuid()).collect();
+        for bd in &bds {
+            info!(
+                "{}",
+                with_dev_context(
+                    pool_uuid,
+                    Some(bd.uuid()),
+                    &bd.devnode,
+                    format_args!("added to pool")
+                )
+            );
+        }
+        self.avail_space += bds.iter().map(|bd| bd.available()).sum();
         self.block_devs.extend(bds);
         Ok(bdev_uuids)
     }
 
+    /// Return an error if the metadata most recently saved would not fit in
+    /// MDA regions of mda_size sectors, so that devices that could never
+    /// hold the pool's metadata are refused before they are initialized,
+    /// rather than failing at the next save of metadata.
+    fn check_metadata_fits(&self, mda_size: Sectors) -> StratisResult<()> {
+        if let Some((_, data)) = self.last_metadata() {
+            let max_len = max_metadata_len(mda_size);
+            if Bytes(data.len() as u64) > max_len {
+                let err_msg = format!(
+                    "pool metadata of {} bytes exceeds the {} bytes that an MDA of {} \
+                     could hold, so no new device could store it",
+                    data.len(),
+                    *max_len,
+                    mda_size
+                );
+                return Err(StratisError::Engine(ErrorEnum::NotEnoughSpace, err_msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// If the data on the blockdevs is encrypted, encrypt the data on bds,
+    /// blockdevs just initialized to be added to self, with the same key,
+    /// so that no plaintext blockdev joins an encrypted pool.
+    fn encrypt_new(&self, bds: &mut [StratBlockDev]) -> StratisResult<()> {
+        match self.key_description() {
+            Some(key_desc) => unlock_new(bds, key_desc),
+            None => Ok(()),
+        }
+    }
+
     pub fn destroy_all(&mut self) -> StratisResult<()> {
+        self.check_writable("destroy blockdevs")?;
+        self.teardown()?;
         wipe_blockdevs(&self.block_devs)
     }
 
+    /// Tear down the dm-crypt devices of the blockdevs whose data is
+    /// encrypted. The blockdevs are unlocked again when the pool is next
+    /// set up.
+    pub fn teardown(&mut self) -> StratisResult<()> {
+        for bd in &mut self.block_devs {
+            bd.lock()?;
+        }
+        Ok(())
+    }
+
+    /// Quiesce the blockdevs before a clean shutdown. The metadata most
+    /// recently saved is written to every blockdev that has not yet got it,
+    /// and every blockdev is synced. No exclusive opens are held between
+    /// operations, so none remain to be released.
+    /// Afterward, every operation that would change the blockdevs or their
+    /// allocations is refused. The BlockDevMgr is shut down even if an
+    /// error is returned; the first error encountered is returned, but
+    /// every blockdev is still synced. Calling this again does nothing.
+    pub fn shutdown(&mut self) -> StratisResult<()> {
+        if self.shut_down {
+            return Ok(());
+        }
+        self.shut_down = true;
+
+        if let Some((time, ref data)) = self.last_metadata {
+            let data_size = Bytes(data.len() as u64).sectors();
+            for bd in self.block_devs.iter_mut().filter(|b| {
+                b.max_metadata_size() >= data_size && b.last_update_time() < Some(&time)
+            }) {
+                // The metadata is already on at least one blockdev, so
+                // not getting it onto another is not an error.
+                if let Err(err) = bd.save_state(&time, data) {
+                    warn!(
+                        "{}",
+                        with_dev_context(
+                            bd.pool_uuid(),
+                            Some(bd.uuid()),
+                            &bd.devnode,
+                            format_args!("failed to save metadata: {}", err)
+                        )
+                    );
+                }
+            }
+        }
+
+        let mut result = Ok(());
+        for bd in &self.block_devs {
+            if let Err(err) = bd.sync() {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
     /// Allocate space according to sizes vector request.
-    /// Return the segments allocated for each request, or None if it was
-    /// not possible to satisfy the request.
+    /// Return the segments allocated for each request, one list for each
+    /// size, in the order of sizes, or None if it was not possible to
+    /// satisfy the request.
     /// This method is atomic, it either allocates all requested or allocates
-    /// nothing.
+    /// nothing: the sizes are satisfied together or not at all.
     pub fn alloc_space(&mut self, sizes: &[Sectors]) -> Option<Vec<Vec<BlkDevSegment>>> {
+        if let Err(err) = self.check_writable("allocate space") {
+            warn!("{}", err);
+            return None;
+        }
+
         let total_needed: Sectors = sizes.iter().cloned().sum();
-        if self.avail_space() < total_needed {
+        if self.max_allocatable(false) < total_needed {
             return None;
         }
 
-        let mut lists = Vec::new();
+        let mut planned = Vec::new();
         for &needed in sizes {
-            let mut alloc = Sectors(0);
-            let mut segs = Vec::new();
-            // TODO: Consider greater efficiency for allocation generally.
-            // Over time, the blockdevs at the start will be exhausted. It
-            // might be a good idea to keep an auxiliary structure, so that
-            // only blockdevs with some space left to allocate are accessed.
-            // In the context of this major inefficiency that ensues over time
-            // the obvious but more minor inefficiency of this inner loop is
-            // not worth worrying about.
-            for bd in &mut self.block_devs {
-                if alloc == needed {
-                    break;
+            let mut ranges = Vec::new();
+            let result = plan_request(&mut self.block_devs, needed, &mut ranges, |bd, wanted| {
+                bd.request_space(wanted)
+            });
+            planned.push(ranges);
+            if let Err(err) = result {
+                error!("{}, releasing the sectors already allocated", err);
+                for &(i, start, length) in planned.iter().flat_map(|ranges| ranges.iter()) {
+                    let _ = self.block_devs[i].release_space(&[(start, length)]);
                 }
-
-                let (gotten, r_segs) = bd.request_space(needed - alloc);
-                let blkdev_segs = r_segs.into_iter().map(|(start, length)| {
-                    BlkDevSegment::new(bd.uuid(), Segment::new(*bd.device(), start, length))
-                });
-                segs.extend(blkdev_segs);
-                alloc += gotten;
+                return None;
             }
-            assert_eq!(alloc, needed);
-            lists.push(segs);
         }
+        self.avail_space -= total_needed;
+
+        let block_devs = &self.block_devs;
+        let lists = planned
+            .into_iter()
+            .map(|ranges| {
+                ranges
+                    .into_iter()
+                    .map(|(i, start, length)| {
+                        let bd = &block_devs[i];
+                        let segment = Segment::new(bd.data_device(), start, length);
+                        BlkDevSegment::new(bd.uuid(), segment)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        debug_assert!(self.check_invariants().is_ok());
 
         Some(lists)
     }
 
+    /// The unit in which space is allocated by allocations that round their
+    /// requests with round_to_chunk().
+    pub fn alloc_chunk(&self) -> Sectors {
+        self.alloc_chunk
+    }
+
+    /// Set the unit in which space is allocated. Allocating in larger units
+    /// yields fewer, larger segments over the life of the pool. Returns an
+    /// error if the chunk is empty.
+    pub fn set_alloc_chunk(&mut self, chunk: Sectors) -> StratisResult<()> {
+        if chunk == Sectors(0) {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "allocation chunk size must be at least one sector".into(),
+            ));
+        }
+        self.alloc_chunk = chunk;
+        Ok(())
+    }
+
+    /// The size of a request rounded up to a whole number of allocation
+    /// chunks, so that everything allocated is a multiple of the chunk.
+    /// A request that can be satisfied only if it is not rounded must
+    /// fail, like any other. Returns None if the rounded size would
+    /// overflow.
+    pub fn round_to_chunk(&self, request: Sectors) -> Option<Sectors> {
+        let remainder = request % self.alloc_chunk;
+        if remainder == Sectors(0) {
+            return Some(request);
+        }
+        request.checked_add(self.alloc_chunk - remainder)
+    }
+
+    /// The fewest blockdevs with which a pool may be made, and the fewest
+    /// that may be left in service.
+    pub fn min_devices(&self) -> usize {
+        self.min_devices
+    }
+
+    /// Set the fewest blockdevs that may be left in service, as when the
+    /// setting recorded in the pool metadata is restored. Blockdevs already
+    /// out of service are unaffected.
+    pub fn set_min_devices(&mut self, min_devices: usize) {
+        self.min_devices = min_devices;
+    }
+
+    /// Return an error if count devices are too few to make a pool of.
+    fn check_min_devices(&self, count: usize) -> StratisResult<()> {
+        if count < self.min_devices {
+            let err_msg = format!(
+                "{} usable devices specified, but a pool requires at least {}",
+                count, self.min_devices
+            );
+            return Err(StratisError::Engine(ErrorEnum::PolicyViolation, err_msg));
+        }
+        Ok(())
+    }
+
+    /// Check that metadata is pool metadata that could be used to set up
+    /// the pool again. The metadata must parse as a PoolSave, every data
+    /// device it records must be managed by self, and every data segment
+    /// must belong to a recorded data device.
+    pub fn validate_metadata(&self, metadata: &[u8]) -> StratisResult<()> {
+        let pool_save: PoolSave = serde_json::from_slice(metadata).map_err(|err| {
+            let err_msg = format!("metadata is not valid pool metadata: {}", err);
+            StratisError::Engine(ErrorEnum::Invalid, err_msg)
+        })?;
+
+        let backstore_save = &pool_save.backstore;
+        let recorded_uuids: HashSet<DevUuid> =
+            backstore_save.data_devs.iter().map(|bds| bds.uuid).collect();
+
+        if let Some(uuid) = recorded_uuids
+            .iter()
+            .find(|&&uuid| self.get_blockdev_by_uuid(uuid).is_none())
+        {
+            let err_msg = format!(
+                "metadata records data device with UUID {}, which is not in the pool",
+                uuid
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        if let Some(&(uuid, _, _)) = backstore_save
+            .data_segments
+            .iter()
+            .find(|&&(uuid, _, _)| !recorded_uuids.contains(&uuid))
+        {
+            let err_msg = format!(
+                "metadata records a data segment on device with UUID {}, which is not a recorded data device",
+                uuid
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        Ok(())
+    }
+
     /// Write the given data to all blockdevs marking with current time.
+    /// Return an error if the data is not valid pool metadata.
     /// Return an error if data was not written to any blockdev.
     /// Omit blockdevs which do not have sufficient space in BDA to accommodate
     /// metadata. If current time is not more recent than previously written
     /// time, use a time that is one nanosecond greater than that previously
     /// written. Randomly select no more than MAX_NUM_TO_WRITE blockdevs to
-    /// write to.
+    /// write to. If the data is identical to that most recently saved,
+    /// write nothing, leaving the time of the last update unchanged.
     pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<()> {
+        self.check_writable("save metadata")?;
+        self.validate_metadata(metadata)?;
+
+        if self.last_metadata().map(|(_, data)| data) == Some(metadata) {
+            return Ok(());
+        }
+
         let current_time = Utc::now();
         let stamp_time = if Some(current_time) <= self.last_update_time {
             self.last_update_time
@@ -277,21 +757,72 @@ impl BlockDevMgr {
         };
 
         let data_size = Bytes(metadata.len() as u64).sectors();
+        let max_concurrent_writes = self.max_concurrent_writes;
         let candidates = self.block_devs
-            .iter_mut()
-            .filter(|b| b.max_metadata_size() >= data_size);
+            .iter()
+            .enumerate()
+            .filter(|&(_, b)| b.max_metadata_size() >= data_size)
+            .map(|(index, _)| index);
 
         // TODO: consider making selection not entirely random, i.e, ensuring
         // distribution of metadata over different paths.
-        let saved = seq::sample_iter(&mut thread_rng(), candidates, MAX_NUM_TO_WRITE)
-            .unwrap_or_else(|e| e)
-            .iter_mut()
-            .fold(false, |acc, b| {
-                acc | b.save_state(&stamp_time, metadata).is_ok()
-            });
+        let mut indices = seq::sample_iter(&mut thread_rng(), candidates, MAX_NUM_TO_WRITE)
+            .unwrap_or_else(|e| e);
+        indices.sort_unstable();
+
+        // The blockdevs selected are taken out of self while they are
+        // written, so that each can be moved to a thread of its own,
+        // and are put back in their places afterward.
+        let mut selected = Vec::with_capacity(indices.len());
+        for &index in indices.iter().rev() {
+            selected.push((index, self.block_devs.remove(index)));
+        }
+        selected.reverse();
+
+        // The writes are made concurrently, so that one slow blockdev does
+        // not delay the others, but no more than max_concurrent_writes at
+        // once, so that the blockdevs of a large pool behind a single
+        // controller are not all written at once.
+        let shared = Arc::new(metadata.to_vec());
+        let mut saved = false;
+        let mut written = Vec::with_capacity(selected.len());
+        while !selected.is_empty() {
+            let rest = selected.split_off(min(max_concurrent_writes, selected.len()));
+            let writes: Vec<_> = selected
+                .into_iter()
+                .map(|(index, mut b)| {
+                    let metadata = Arc::clone(&shared);
+                    thread::spawn(move || {
+                        let result = b.save_state(&stamp_time, &metadata);
+                        (index, b, result)
+                    })
+                })
+                .collect();
+            for write in writes {
+                let (index, b, result) = write.join().expect("saving metadata does not panic");
+                match result {
+                    Ok(_) => saved = true,
+                    Err(err) => warn!(
+                        "{}",
+                        with_dev_context(
+                            b.pool_uuid(),
+                            Some(b.uuid()),
+                            &b.devnode,
+                            format_args!("failed to save metadata: {}", err)
+                        )
+                    ),
+                }
+                written.push((index, b));
+            }
+            selected = rest;
+        }
+        for (index, b) in written {
+            self.block_devs.insert(index, b);
+        }
 
         if saved {
             self.last_update_time = Some(stamp_time);
+            self.last_metadata = Some((stamp_time, metadata.to_vec()));
             Ok(())
         } else {
             let err_msg = "Failed to save metadata to even one device in pool";
@@ -299,6 +830,80 @@ impl BlockDevMgr {
         }
     }
 
+    /// The metadata most recently saved by this BlockDevMgr, with the time
+    /// it was saved with, or, if it has saved none since it was set up, that
+    /// read back by load_last_metadata(). None if there is neither.
+    pub fn last_metadata(&self) -> Option<(&DateTime<Utc>, &[u8])> {
+        self.last_metadata
+            .as_ref()
+            .map(|(time, data)| (time, data.as_slice()))
+    }
+
+    /// Read back the newest metadata that can be read from any blockdev,
+    /// and take it to be the metadata most recently saved, as when a pool
+    /// is set up, so that the checks of the room for the metadata apply
+    /// from the first. The time of the last update is
+    /// advanced to the time of that metadata if it is earlier.
+    /// If no metadata can be read back, none is taken to have been saved.
+    pub fn load_last_metadata(&mut self) {
+        let mut saved: Vec<(DateTime<Utc>, &StratBlockDev)> = self.block_devs
+            .iter()
+            .filter_map(|bd| bd.last_update_time().map(|time| (*time, bd)))
+            .collect();
+        saved.sort_by_key(|&(time, _)| Reverse(time));
+        let newest = saved
+            .iter()
+            .filter_map(|&(time, bd)| match bd.load_state() {
+                Ok(Some(data)) => Some((time, data)),
+                _ => None,
+            })
+            .next();
+        if let Some((time, data)) = newest {
+            if self.last_update_time < Some(time) {
+                self.last_update_time = Some(time);
+            }
+            self.last_metadata = Some((time, data));
+        }
+    }
+
+    /// The number of blockdevs that could be lost without losing the
+    /// current metadata, i.e., one less than the number of blockdevs that
+    /// hold an intact copy of the metadata with the time of the last update.
+    /// 0 if no blockdev holds one, as when none has been saved. Each copy is
+    /// read back from its blockdev, so that a copy that can not be read,
+    /// that does not match its recorded checksum, or that differs from the
+    /// metadata last saved is not counted.
+    pub fn metadata_redundancy(&self) -> usize {
+        let current = match self.last_update_time {
+            Some(ref time) => time,
+            None => return 0,
+        };
+        let last_data = self.last_metadata.as_ref().map(|(_, data)| data);
+        self.block_devs
+            .iter()
+            .filter(|bd| bd.last_update_time() == Some(current))
+            .filter(|bd| match bd.load_state() {
+                Ok(Some(ref data)) => last_data.filter(|last| *last != data).is_none(),
+                _ => false,
+            })
+            .count()
+            .saturating_sub(1)
+    }
+
+    /// Write garbage over MDA region slot of the blockdev with the given
+    /// UUID, so that tests can verify that the metadata is recovered from
+    /// the other regions and the other blockdevs. Slots 2 and 3 are the
+    /// copies of slots 0 and 1.
+    #[cfg(test)]
+    pub fn corrupt_slot(&mut self, uuid: DevUuid, slot: usize) -> StratisResult<()> {
+        self.get_blockdev_by_uuid(uuid)
+            .ok_or_else(|| {
+                let err_msg = format!("no blockdev with UUID {} in this tier", uuid);
+                StratisError::Engine(ErrorEnum::NotFound, err_msg)
+            })?
+            .corrupt_mda_region(slot)
+    }
+
     /// Get references to managed blockdevs.
     pub fn blockdevs(&self) -> Vec<(DevUuid, &StratBlockDev)> {
         self.block_devs.iter().map(|bd| (bd.uuid(), bd)).collect()
@@ -315,199 +920,1231 @@ impl BlockDevMgr {
         self.block_devs.iter().find(|bd| bd.uuid() == uuid)
     }
 
+    /// Only setters of the blockdev's own fields, like set_user_info(), are
+    /// public; changes that affect allocation go through self, so that the
+    /// running total of available space is kept.
     pub fn get_mut_blockdev_by_uuid(&mut self, uuid: DevUuid) -> Option<&mut StratBlockDev> {
         self.block_devs.iter_mut().find(|bd| bd.uuid() == uuid)
     }
 
+    pub fn get_blockdev_by_device(&self, device: Device) -> Option<&StratBlockDev> {
+        self.block_devs.iter().find(|bd| *bd.device() == device)
+    }
+
     // SIZE methods
 
     /// The number of sectors not allocated for any purpose.
     pub fn avail_space(&self) -> Sectors {
+        debug_assert_eq!(self.avail_space, self.sum_avail_space());
+        self.avail_space
+    }
+
+    /// The number of sectors not allocated for any purpose, summed over
+    /// the blockdevs rather than taken from the running total.
+    fn sum_avail_space(&self) -> Sectors {
         self.block_devs.iter().map(|bd| bd.available()).sum()
     }
 
+    /// Bound the number of metadata writes to blockdevs that may be in
+    /// progress at once when saving metadata, so that saving metadata on a
+    /// large pool does not flood a shared controller. Returns an error if
+    /// the bound is 0.
+    pub fn set_max_concurrent_writes(&mut self, max: usize) -> StratisResult<()> {
+        if max == 0 {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "at least one metadata write must be permitted at a time".into(),
+            ));
+        }
+        self.max_concurrent_writes = max;
+        Ok(())
+    }
+
+    /// The largest single allocation that could be satisfied now.
+    /// If contiguous is true, this is the length of the largest contiguous
+    /// run of unallocated sectors on any one blockdev, otherwise it is the
+    /// total number of unallocated sectors, since alloc_space() may satisfy
+    /// a request with segments from several blockdevs.
+    pub fn max_allocatable(&self, contiguous: bool) -> Sectors {
+        if contiguous {
+            self.block_devs
+                .iter()
+                .map(|bd| bd.largest_available())
+                .max()
+                .unwrap_or(Sectors(0))
+        } else {
+            self.block_devs.iter().map(|bd| bd.available()).sum()
+        }
+    }
+
     /// The current capacity of all the blockdevs.
     /// self.current_capacity() > self.avail_space() because some sectors
     /// are certainly allocated for Stratis metadata
     pub fn current_capacity(&self) -> Sectors {
-        self.block_devs.iter().map(|b| b.current_capacity()).sum()
+        self.block_devs
+            .iter()
+            .map(|b| b.current_capacity())
+            .sum()
+    }
+
+    /// The size of the MDA regions of the blockdevs. This is the size of
+    /// the largest MDA regions of any blockdev, or the minimum size if there
+    /// are no blockdevs.
+    pub fn mda_size(&self) -> Sectors {
+        self.block_devs
+            .iter()
+            .map(|bd| bd.mda_size())
+            .max()
+            .unwrap_or(MIN_MDA_SECTORS)
     }
 
     /// The number of sectors given over to Stratis metadata
     /// self.current_capacity() - self.metadata_size() >= self.avail_space()
     pub fn metadata_size(&self) -> Sectors {
-        self.block_devs.iter().map(|bd| bd.metadata_size()).sum()
+        self.block_devs
+            .iter()
+            .map(|bd| bd.metadata_size())
+            .sum()
+    }
+
+    /// Verify that no two blockdevs claim the same Stratis UUID, as would be
+    /// the case if one device had been cloned from another. Return an error
+    /// naming the UUID and both devnodes of the first duplicate found.
+    pub fn check_unique_uuids(&self) -> StratisResult<()> {
+        let mut devnodes: HashMap<DevUuid, &Path> = HashMap::new();
+        for bd in &self.block_devs {
+            if let Some(other) = devnodes.insert(bd.uuid(), &bd.devnode) {
+                let err_msg = format!(
+                    "Stratis device UUID {} is claimed by more than one device: {} and {}",
+                    bd.uuid(),
+                    other.display(),
+                    bd.devnode.display()
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// Verify that the allocation state of every blockdev is consistent.
+    /// Return an error describing the first inconsistency found.
+    pub fn check_invariants(&self) -> StratisResult<()> {
+        for bd in &self.block_devs {
+            bd.check_invariants()?;
+        }
+        Ok(())
     }
 }
 
 impl Recordable<Vec<BlockDevSave>> for BlockDevMgr {
+    /// The blockdevs are recorded in order of UUID, so that the same
+    /// blockdevs always yield the same metadata, whatever order they were
+    /// added or discovered in.
     fn record(&self) -> Vec<BlockDevSave> {
-        self.block_devs.iter().map(|bd| bd.record()).collect()
+        let mut saves: Vec<BlockDevSave> = self.block_devs.iter().map(|bd| bd.record()).collect();
+        saves.sort_by_key(|save| save.uuid);
+        saves
     }
 }
 
-/// Initialize multiple blockdevs at once. This allows all of them
-/// to be checked for usability before writing to any of them.
-fn initialize(
-    pool_uuid: PoolUuid,
-    devices: HashMap<Device, &Path>,
-    mda_size: Sectors,
-    force: bool,
-    owned_devs: &HashSet<DevUuid>,
-) -> StratisResult<Vec<StratBlockDev>> {
-    /// Get device information, returns an error if problem with obtaining
-    /// that information.
-    /// Returns a tuple with the device's path, its size in bytes,
-    /// its signature as determined by calling device::identify(),
-    /// and an open File handle, all of which are needed later.
-    fn dev_info(devnode: &Path) -> StratisResult<(&Path, Bytes, DevOwnership, File)> {
-        let f = OpenOptions::new().read(true).write(true).open(&devnode)?;
-        let dev_size = blkdev_size(&f)?;
-        let ownership = identify(devnode)?;
-
-        Ok((devnode, dev_size, ownership, f))
-    }
-
-    /// Filter devices for admission to pool based on dev_infos.
-    /// If there is an error finding out the info, return that error.
-    /// Also, return an error if a device is not appropriate for this pool.
-    #[allow(type_complexity)]
-    fn filter_devs<'a, I>(
-        dev_infos: I,
-        pool_uuid: PoolUuid,
-        force: bool,
-        owned_devs: &HashSet<DevUuid>,
-    ) -> StratisResult<Vec<(Device, (&'a Path, Bytes, File))>>
-    where
-        I: Iterator<Item = (Device, StratisResult<(&'a Path, Bytes, DevOwnership, File)>)>,
-    {
-        let mut add_devs = Vec::new();
-        for (dev, dev_result) in dev_infos {
-            let (devnode, dev_size, ownership, mut f) = dev_result?;
-            if dev_size < MIN_DEV_SIZE {
-                let error_message = format!(
-                    "{} too small, minimum {} bytes",
-                    devnode.display(),
-                    MIN_DEV_SIZE
-                );
-                return Err(StratisError::Engine(ErrorEnum::Invalid, error_message));
-            };
-            match ownership {
-                DevOwnership::Unowned => add_devs.push((dev, (devnode, dev_size, f))),
-                DevOwnership::Theirs(signature) => {
-                    if !force {
-                        let err_str = format!(
-                            "Device {} has an existing signature {}",
-                            devnode.display(),
-                            signature
-                        );
-                        return Err(StratisError::Engine(ErrorEnum::Invalid, err_str));
-                    } else {
-                        add_devs.push((dev, (devnode, dev_size, f)))
-                    }
-                }
-                DevOwnership::Ours(uuid, dev_uuid) => {
-                    if pool_uuid == uuid {
-                        if !owned_devs.contains(&dev_uuid) {
-                            let error_str = format!(
-                                "Device {} with pool UUID is unknown to pool",
-                                devnode.display()
-                            );
-                            return Err(StratisError::Engine(ErrorEnum::Invalid, error_str));
-                        }
-                    } else {
-                        let error_str = format!(
-                            "Device {} already belongs to Stratis pool {}",
-                            devnode.display(),
-                            uuid
-                        );
-                        return Err(StratisError::Engine(ErrorEnum::Invalid, error_str));
-                    }
-                }
+/// What to do if a device disappears while a set of devices is being
+/// initialized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceLossPolicy {
+    /// Abandon the initialization, wiping every device already initialized.
+    Abort,
+    /// Omit the lost device and continue to initialize the remaining ones.
+    Skip,
+}
+
+/// Returns true if the error indicates that the device operated on is
+/// no longer present, for example, because it was hot-unplugged.
+fn is_device_loss(err: &StratisError) -> bool {
+    match *err {
+        StratisError::Io(ref err) => match err.raw_os_error() {
+            Some(errno) => errno == ENODEV || errno == ENXIO,
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// The reason a device was refused admission to a pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceRejection {
+    /// Information about the device could not be obtained.
+    Unreadable(String),
+    /// The device reports a size of 0, as a device that is not yet ready
+    /// may, e.g., one that udev has not finished setting up.
+    ZeroSize,
+    /// The device is smaller than the minimum permitted size.
+    TooSmall(Bytes),
+    /// The device, of the first size, can not hold metadata of the second
+    /// size together with the minimum amount of data.
+    NoRoomForData(Bytes, Bytes),
+    /// The device has an existing signature, and force was not specified.
+    Signature(String),
+    /// The device already belongs to the Stratis pool with this UUID.
+    OtherPool(PoolUuid),
+    /// The device claims to belong to this pool, but the pool does not
+    /// know of it.
+    UnknownToPool,
+    /// The device is a partition, and this device, which is the disk that
+    /// contains it or another partition of the disk, is in use.
+    PartitionInUse(Device),
+    /// A filesystem on the device is mounted.
+    Mounted,
+    /// The device has both a BDA of the Stratis pool with this UUID and
+    /// this foreign signature, so it can not be known which is in use.
+    Ambiguous(PoolUuid, String),
+}
+
+impl DeviceRejection {
+    /// An error describing the rejection of the device at devnode.
+    pub fn to_error(&self, devnode: &Path) -> StratisError {
+        let err_msg = match *self {
+            DeviceRejection::Unreadable(ref err) => format!(
+                "Information about device {} could not be obtained: {}",
+                devnode.display(),
+                err
+            ),
+            DeviceRejection::ZeroSize => format!(
+                "Device {} reports a size of 0 bytes; is it ready?",
+                devnode.display()
+            ),
+            DeviceRejection::TooSmall(_) => format!(
+                "{} too small, minimum {} bytes",
+                devnode.display(),
+                MIN_DEV_SIZE
+            ),
+            DeviceRejection::NoRoomForData(dev_size, metadata_size) => format!(
+                "{} too small: its {} bytes can not hold {} bytes of metadata and at least {} bytes of data",
+                devnode.display(),
+                dev_size,
+                metadata_size,
+                MIN_DATA_SIZE
+            ),
+            DeviceRejection::Signature(ref signature) => format!(
+                "Device {} has an existing signature {}",
+                devnode.display(),
+                signature
+            ),
+            DeviceRejection::OtherPool(uuid) => format!(
+                "Device {} already belongs to Stratis pool {}",
+                devnode.display(),
+                uuid
+            ),
+            DeviceRejection::UnknownToPool => format!(
+                "Device {} with pool UUID is unknown to pool",
+                devnode.display()
+            ),
+            DeviceRejection::PartitionInUse(device) => format!(
+                "Device {} is a partition of a disk in use: device {} is mounted or held",
+                devnode.display(),
+                device
+            ),
+            DeviceRejection::Mounted => format!(
+                "Device {} has a mounted filesystem",
+                devnode.display()
+            ),
+            DeviceRejection::Ambiguous(uuid, ref signature) => format!(
+                "Device {} has both a BDA of Stratis pool {} and a signature {}",
+                devnode.display(),
+                uuid,
+                signature
+            ),
+        };
+        let kind = match *self {
+            DeviceRejection::Unreadable(_) => ErrorEnum::Error,
+            DeviceRejection::ZeroSize => ErrorEnum::Busy,
+            DeviceRejection::TooSmall(_) | DeviceRejection::NoRoomForData(_, _) => {
+                ErrorEnum::NotEnoughSpace
             }
-        }
-        Ok(add_devs)
+            DeviceRejection::Signature(_)
+            | DeviceRejection::OtherPool(_)
+            | DeviceRejection::UnknownToPool
+            | DeviceRejection::PartitionInUse(_)
+            | DeviceRejection::Mounted
+            | DeviceRejection::Ambiguous(_, _) => ErrorEnum::PolicyViolation,
+        };
+        StratisError::Engine(kind, err_msg)
     }
+}
 
-    validate_mda_size(mda_size)?;
+/// Get device information, returns an error if problem with obtaining
+/// that information.
+/// Returns a tuple with the device's path, its size in bytes,
+/// its signature as determined by calling device::identify(),
+/// and an open File handle, all of which are needed later.
+/// The device is opened exclusively, so that the kernel refuses the open
+/// with a Busy error if the device is mounted or otherwise claimed, and
+/// prevents others from claiming it while the handle is open.
+fn dev_info(devnode: &Path) -> StratisResult<(&Path, Bytes, DevOwnership, File)> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_EXCL)
+        .open(&devnode)
+        .map_err(|err| {
+            if err.raw_os_error() == Some(EBUSY) {
+                let err_msg = format!("device {} is in use", devnode.display());
+                StratisError::Engine(ErrorEnum::Busy, err_msg)
+            } else {
+                StratisError::Io(err)
+            }
+        })?;
+    let dev_size = blkdev_size(&f)?;
+    let ownership = identify(devnode)?;
 
-    let dev_infos = devices.into_iter().map(|(d, p)| (d, dev_info(p)));
+    Ok((devnode, dev_size, ownership, f))
+}
 
-    let add_devs = filter_devs(dev_infos, pool_uuid, force, owned_devs)?;
+/// Take needed sectors from the sources in turn, as much from each as
+/// request, called with the source and the number of sectors still needed,
+/// grants.
+/// Append the (index of source, start, length) of each range taken to ranges.
+/// It is the responsibility of the caller to have made sure that the
+/// sources can grant needed sectors in all. If they can not, an error is
+/// returned, and the ranges taken are left in ranges, so that the caller
+/// may give them back.
+// TODO: Consider greater efficiency for allocation generally.
+// Over time, the blockdevs at the start will be exhausted. It
+// might be a good idea to keep an auxiliary structure, so that
+// only blockdevs with some space left to allocate are accessed.
+// In the context of this major inefficiency that ensues over time
+// the obvious but more minor inefficiency of this inner loop is
+// not worth worrying about.
+fn plan_request<T, F>(
+    sources: &mut [T],
+    needed: Sectors,
+    ranges: &mut Vec<(usize, Sectors, Sectors)>,
+    mut request: F,
+) -> StratisResult<()>
+where
+    F: FnMut(&mut T, Sectors) -> (Sectors, Vec<(Sectors, Sectors)>),
+{
+    let mut alloc = Sectors(0);
+    for (i, source) in sources.iter_mut().enumerate() {
+        if alloc == needed {
+            break;
+        }
 
-    let mut bds: Vec<StratBlockDev> = Vec::new();
-    for (dev, (devnode, dev_size, mut f)) in add_devs {
-        let bda = BDA::initialize(
-            &mut f,
-            pool_uuid,
-            Uuid::new_v4(),
-            mda_size,
-            dev_size.sectors(),
-            Utc::now().timestamp() as u64,
+        let (gotten, r_segs) = request(source, needed - alloc);
+        ranges.extend(r_segs.into_iter().map(|(start, length)| (i, start, length)));
+        alloc += gotten;
+    }
+    if alloc != needed {
+        let err_msg = format!(
+            "only {} of the {} sectors requested could be allocated",
+            alloc, needed
         );
-        if let Ok(bda) = bda {
-            let hw_id = match hw_lookup(devnode) {
-                Ok(id) => id,
-                Err(_) => None, // TODO: Log this failure so that it can be addressed.
-            };
-
-            // FIXME: The expect is only provisionally true.
-            // The dev_size is at least MIN_DEV_SIZE, but the size of the
-            // metadata is not really bounded from above.
-            let blockdev = StratBlockDev::new(dev, devnode.to_owned(), bda, &[], None, hw_id)
-                .expect("bda.size() == dev_size; only allocating space for metadata");
-            bds.push(blockdev);
-        } else {
-            // TODO: check the return values and update state machine on failure
-            let _ = BDA::wipe(&mut f);
-            let _ = wipe_blockdevs(&bds);
+        return Err(StratisError::Engine(ErrorEnum::NotEnoughSpace, err_msg));
+    }
+    Ok(())
+}
 
-            return Err(bda.unwrap_err());
+/// Encrypt the data on bds, blockdevs just initialized, with the key
+/// described by key_desc. If any of them can not be unlocked, all of them
+/// are wiped, so that none is left half set up.
+fn unlock_new(bds: &mut [StratBlockDev], key_desc: &str) -> StratisResult<()> {
+    if let Err(err) = bds.iter_mut()
+        .map(|bd| bd.unlock(key_desc))
+        .collect::<StratisResult<Vec<_>>>()
+    {
+        error!(
+            "setting up encryption failed, wiping all devices initialized: {}",
+            err
+        );
+        // TODO: check the return values and update state machine on failure
+        for bd in bds.iter_mut() {
+            let _ = bd.lock();
         }
+        let _ = wipe_blockdevs(bds);
+        return Err(err);
     }
-    Ok(bds)
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fs::OpenOptions;
-
-    use rand;
-    use uuid::Uuid;
-
-    use super::super::super::tests::{loopbacked, real};
+/// Refuse a device that is a partition of a disk that is otherwise in
+/// use, since claiming it would risk the disk's other contents.
+fn check_partition(dev: Device) -> Result<(), DeviceRejection> {
+    match partition_sibling_in_use(dev) {
+        Ok(None) => Ok(()),
+        Ok(Some(in_use)) => Err(DeviceRejection::PartitionInUse(in_use)),
+        Err(err) => Err(DeviceRejection::Unreadable(err.to_string())),
+    }
+}
 
-    use super::super::metadata::{StaticHeader, MIN_MDA_SECTORS};
-    use super::super::setup::{find_all, get_metadata};
+/// Refuse a device with a mounted filesystem, whatever its ownership and
+/// whatever force permits, since overwriting a filesystem that is in use is
+/// far more destructive than overwriting an idle one.
+fn check_mounted(dev: Device, mounted: &HashSet<Device>) -> Result<(), DeviceRejection> {
+    if mounted.contains(&dev) {
+        Err(DeviceRejection::Mounted)
+    } else {
+        Ok(())
+    }
+}
 
-    use super::super::super::cmd;
+/// Permission to overwrite a device that has a signature that does not
+/// belong to Stratis. The functions that admit devices to a pool require
+/// an Option<ForceToken> rather than a bool, so that overwriting a device
+/// is never the result of passing the wrong boolean; the token can be
+/// obtained only from an explicitly named constructor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForceToken {
+    _private: (),
+}
 
-    use super::*;
+impl ForceToken {
+    /// Grant permission to overwrite devices with foreign signatures.
+    pub fn overwrite_foreign() -> ForceToken {
+        ForceToken { _private: () }
+    }
 
-    /// Returns true if two usages have the same type.  This is needed because
-    /// if Usage::Theirs(String::from("foo") != Usage::Theirs(String::from("bar").
-    /// TODO: See if there is a better way to solve this.
-    fn usage_equal(left: &DevOwnership, right: &DevOwnership) -> bool {
-        if left == right {
-            true
+    /// The permission corresponding to the force flag specified by the
+    /// user. This is the only place where a flag is converted to a token.
+    pub fn from_user_flag(force: bool) -> Option<ForceToken> {
+        if force {
+            Some(ForceToken::overwrite_foreign())
         } else {
-            match left {
-                &DevOwnership::Theirs(_) => match right {
-                    &DevOwnership::Theirs(_) => true,
-                    _ => false,
-                },
-                _ => false,
+            None
+        }
+    }
+}
+
+/// Determine whether a device is appropriate for admission to this pool,
+/// with MDA regions of mda_size sectors.
+/// Returns true if the device should be initialized, false if it already
+/// belongs to the pool and should be left alone, and the reason for
+/// refusing it otherwise.
+fn check_dev(
+    dev_size: Bytes,
+    mda_size: Sectors,
+    ownership: &DevOwnership,
+    pool_uuid: PoolUuid,
+    force: Option<ForceToken>,
+    owned_devs: &HashSet<DevUuid>,
+) -> Result<bool, DeviceRejection> {
+    if dev_size == Bytes(0) {
+        return Err(DeviceRejection::ZeroSize);
+    }
+    if dev_size < MIN_DEV_SIZE {
+        return Err(DeviceRejection::TooSmall(dev_size));
+    };
+    let metadata_size = bda_size(mda_size).bytes();
+    if dev_size < metadata_size + MIN_DATA_SIZE {
+        return Err(DeviceRejection::NoRoomForData(dev_size, metadata_size));
+    }
+    match *ownership {
+        DevOwnership::Unowned => Ok(true),
+        DevOwnership::Theirs(ref signature) => match force {
+            None => Err(DeviceRejection::Signature(signature.clone())),
+            Some(_) => Ok(true),
+        },
+        DevOwnership::Ours(uuid, dev_uuid) => {
+            if pool_uuid == uuid {
+                if !owned_devs.contains(&dev_uuid) {
+                    Err(DeviceRejection::UnknownToPool)
+                } else {
+                    Ok(false)
+                }
+            } else {
+                Err(DeviceRejection::OtherPool(uuid))
+            }
+        }
+        DevOwnership::Ambiguous(uuid, dev_uuid, ref signature) => {
+            if pool_uuid == uuid && owned_devs.contains(&dev_uuid) {
+                Ok(false)
+            } else {
+                Err(DeviceRejection::Ambiguous(uuid, signature.clone()))
             }
         }
     }
+}
 
-    /// Verify that initially,
-    /// current_capacity() - metadata_size() = avail_space().
-    /// After 2 Sectors have been allocated, that amount must also be included
-    /// in balance.
-    fn test_blockdevmgr_used(paths: &[&Path]) -> () {
+/// Write Stratis metadata to the device and make a blockdev for it.
+fn initialize_one(
+    pool_uuid: PoolUuid,
+    dev: Device,
+    devnode: &Path,
+    dev_size: Bytes,
+    mda_size: Sectors,
+    f: &mut File,
+) -> StratisResult<StratBlockDev> {
+    let bda = BDA::initialize(
+        f,
+        pool_uuid,
+        Uuid::new_v4(),
+        mda_size,
+        dev_size.sectors(),
+        Utc::now().timestamp() as u64,
+    )?;
+
+    let hw_id = match hw_lookup(devnode) {
+        Ok(id) => id,
+        Err(_) => None, // TODO: Log this failure so that it can be addressed.
+    };
+
+    let thin_backed = thin_backed_lookup(dev).unwrap_or_else(|err| {
+        warn!(
+            "{}",
+            with_dev_context(
+                pool_uuid,
+                Some(bda.dev_uuid()),
+                devnode,
+                format_args!("failed to find out whether device is thin-backed: {}", err)
+            )
+        );
+        false
+    });
+    if thin_backed {
+        warn!(
+            "{}",
+            with_dev_context(
+                pool_uuid,
+                Some(bda.dev_uuid()),
+                devnode,
+                format_args!(
+                    "device appears to be thinly provisioned or sparse; \
+                     its backing store may run out before it is full"
+                )
+            )
+        );
+    }
+
+    // FIXME: The expect is only provisionally true.
+    // The dev_size is at least MIN_DEV_SIZE, but the size of the
+    // metadata is not really bounded from above.
+    Ok(
+        StratBlockDev::new(dev, devnode.to_owned(), bda, &[], None, hw_id)
+            .expect("bda.size() == dev_size; only allocating space for metadata"),
+    )
+}
+
+/// A set of distinct devices, resolved from the paths specified by a
+/// caller, that are candidates for admission to a pool. Both creating a
+/// pool and adding devices to it go through a DeviceSet, so that the same
+/// rules are applied in both cases.
+struct DeviceSet<'a> {
+    devices: HashMap<Device, &'a Path>,
+}
+
+/// The devices of a DeviceSet that have been found appropriate for a pool,
+/// but that have not yet been initialized.
+struct AdmittedDeviceSet<'a> {
+    devices: Vec<(Device, &'a Path, Bytes, File)>,
+}
+
+impl<'a> DeviceSet<'a> {
+    /// Resolve paths to the set of devices they refer to.
+    /// Returns an error if no paths are specified, since that is almost
+    /// certainly a mistake on the part of the caller.
+    fn resolve(paths: &'a [&Path]) -> StratisResult<DeviceSet<'a>> {
+        if paths.is_empty() {
+            return Err(StratisError::Engine(
+                ErrorEnum::Invalid,
+                "no devices specified".into(),
+            ));
+        }
+        Ok(DeviceSet {
+            devices: resolve_devices(paths)?,
+        })
+    }
+
+    /// Remove the devices that is_known finds to be blockdevs already, so
+    /// that they are neither probed nor initialized.
+    fn remove_known<F>(&mut self, is_known: F)
+    where
+        F: Fn(Device) -> bool,
+    {
+        let known: Vec<Device> = self.devices
+            .keys()
+            .cloned()
+            .filter(|&dev| is_known(dev))
+            .collect();
+        for dev in known {
+            let _ = self.devices.remove(&dev);
+        }
+    }
+
+    /// Filter devices for admission to pool, with MDA regions of mda_size
+    /// sectors.
+    /// If there is an error finding out information about a device, return
+    /// that error. Also, return an error if a device is not appropriate for
+    /// this pool. Omit devices that already belong to the pool.
+    fn filter(
+        self,
+        pool_uuid: PoolUuid,
+        mda_size: Sectors,
+        force: Option<ForceToken>,
+        owned_devs: &HashSet<DevUuid>,
+    ) -> StratisResult<AdmittedDeviceSet<'a>> {
+        let mut devices = Vec::new();
+        let mounted = mounted_devices()?;
+        for (dev, devnode) in self.devices {
+            // These are checked before the device is opened, since the
+            // exclusive open would fail first on a device that is in use.
+            check_mounted(dev, &mounted).map_err(|rejection| rejection.to_error(devnode))?;
+            check_partition(dev).map_err(|rejection| rejection.to_error(devnode))?;
+            let (devnode, dev_size, ownership, f) = dev_info(devnode)?;
+            if check_dev(dev_size, mda_size, &ownership, pool_uuid, force, owned_devs)
+                .map_err(|rejection| rejection.to_error(devnode))?
+            {
+                devices.push((dev, devnode, dev_size, f))
+            }
+        }
+        Ok(AdmittedDeviceSet { devices })
+    }
+}
+
+impl<'a> AdmittedDeviceSet<'a> {
+    /// Initialize all the devices at once. Since all of them have already
+    /// been checked for usability, the only failures possible are failures
+    /// to write.
+    /// If a device disappears while it is being initialized, abort or omit
+    /// the device according to loss_policy. Even if devices may be omitted,
+    /// return an error if no device at all could be initialized.
+    fn init(
+        self,
+        pool_uuid: PoolUuid,
+        mda_size: Sectors,
+        loss_policy: DeviceLossPolicy,
+    ) -> StratisResult<Vec<StratBlockDev>> {
+        validate_mda_size(mda_size)?;
+
+        let mut lost_devs = Vec::new();
+        let mut bds: Vec<StratBlockDev> = Vec::new();
+        for (dev, devnode, dev_size, mut f) in self.devices {
+            match initialize_one(pool_uuid, dev, devnode, dev_size, mda_size, &mut f) {
+                Ok(blockdev) => bds.push(blockdev),
+                Err(ref err) if loss_policy == DeviceLossPolicy::Skip && is_device_loss(err) => {
+                    warn!(
+                        "{}",
+                        with_dev_context(
+                            pool_uuid,
+                            None,
+                            devnode,
+                            format_args!(
+                                "device disappeared during initialization, omitting it: {}",
+                                err
+                            )
+                        )
+                    );
+                    lost_devs.push(devnode);
+                }
+                Err(err) => {
+                    error!(
+                        "{}",
+                        with_dev_context(
+                            pool_uuid,
+                            None,
+                            devnode,
+                            format_args!(
+                                "initialization failed, wiping all devices initialized: {}",
+                                err
+                            )
+                        )
+                    );
+                    // TODO: check the return values and update state machine on failure
+                    let _ = BDA::wipe(&mut f);
+                    let _ = wipe_blockdevs(&bds);
+
+                    return Err(err);
+                }
+            }
+        }
+
+        if bds.is_empty() && !lost_devs.is_empty() {
+            let err_msg = format!(
+                "all devices disappeared during initialization: {:?}",
+                lost_devs
+            );
+            return Err(StratisError::Engine(ErrorEnum::NotFound, err_msg));
+        }
+
+        Ok(bds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+
+    use devicemapper::{device_exists, DevId, DmFlags, DmOptions};
+    use nix::mount::{mount, umount, MsFlags};
+    use rand;
+    use tempfile;
+    use uuid::Uuid;
+
+    use super::super::super::tests::{loopbacked, real};
+
+    use super::super::metadata::{StaticHeader, MIN_MDA_SECTORS};
+    use super::super::setup::{find_all, get_blockdevs, get_metadata};
+
+    use super::super::super::dm::get_dm;
+    use super::super::super::dmnames::format_crypt_ids;
+
+    use super::super::super::cmd;
+    use super::super::super::serde_structs::tests::blockdev_save;
+    use super::super::super::serde_structs::{BackstoreSave, FlexDevsSave, ThinPoolDevSave};
+
+    use super::*;
+
+    /// Returns true if two usages have the same type.  This is needed because
+    /// if Usage::Theirs(String::from("foo") != Usage::Theirs(String::from("bar").
+    /// TODO: See if there is a better way to solve this.
+    fn usage_equal(left: &DevOwnership, right: &DevOwnership) -> bool {
+        if left == right {
+            true
+        } else {
+            match left {
+                &DevOwnership::Theirs(_) => match right {
+                    &DevOwnership::Theirs(_) => true,
+                    _ => false,
+                },
+                _ => false,
+            }
+        }
+    }
+
+    #[test]
+    /// Verify that device context identifies the pool, the device, and its
+    /// devnode.
+    fn test_with_dev_context() {
+        let pool_uuid = Uuid::new_v4();
+        let dev_uuid = Uuid::new_v4();
+        let devnode = Path::new("/dev/sdz");
+
+        assert_eq!(
+            with_dev_context(pool_uuid, Some(dev_uuid), devnode, format_args!("x {}", 1)),
+            format!(
+                "pool {} device {} (/dev/sdz): x 1",
+                pool_uuid.simple(),
+                dev_uuid.simple()
+            )
+        );
+        assert_eq!(
+            with_dev_context(pool_uuid, None, devnode, format_args!("x")),
+            format!("pool {} device (/dev/sdz): x", pool_uuid.simple())
+        );
+    }
+
+    #[test]
+    /// Verify that only errors indicating that a device has gone away are
+    /// classified as device loss.
+    fn test_is_device_loss() {
+        use std::io;
+
+        let io_err = |errno| StratisError::Io(io::Error::from_raw_os_error(errno));
+        assert!(is_device_loss(&io_err(ENODEV)));
+        assert!(is_device_loss(&io_err(ENXIO)));
+        assert!(!is_device_loss(&io_err(nix::libc::EIO)));
+        assert!(!is_device_loss(&StratisError::Io(io::Error::from(
+            io::ErrorKind::Other
+        ))));
+        assert!(!is_device_loss(&StratisError::Engine(
+            ErrorEnum::NotFound,
+            "not found".into()
+        )));
+    }
+
+    #[test]
+    /// Verify that check_dev() admits, skips, and rejects devices for the
+    /// right reasons.
+    fn test_check_dev() {
+        let pool_uuid = Uuid::new_v4();
+        let other_pool_uuid = Uuid::new_v4();
+        let dev_uuid = Uuid::new_v4();
+        let owned_devs: HashSet<DevUuid> = [dev_uuid].iter().cloned().collect();
+        let big = MIN_DEV_SIZE;
+        let small = MIN_DEV_SIZE - Bytes(1);
+
+        let force = Some(ForceToken::overwrite_foreign());
+
+        let check = |dev_size, ownership: DevOwnership, force| {
+            check_dev(dev_size, MIN_MDA_SECTORS, &ownership, pool_uuid, force, &owned_devs)
+        };
+
+        assert_eq!(check(big, DevOwnership::Unowned, None), Ok(true));
+        assert_eq!(
+            check(small, DevOwnership::Unowned, force),
+            Err(DeviceRejection::TooSmall(small))
+        );
+        assert_eq!(
+            check(Bytes(0), DevOwnership::Unowned, force),
+            Err(DeviceRejection::ZeroSize)
+        );
+        assert_eq!(
+            check(big, DevOwnership::Theirs("xfs".into()), None),
+            Err(DeviceRejection::Signature("xfs".into()))
+        );
+        assert_eq!(check(big, DevOwnership::Theirs("xfs".into()), force), Ok(true));
+        assert_eq!(
+            check(big, DevOwnership::Ours(pool_uuid, dev_uuid), None),
+            Ok(false)
+        );
+        assert_eq!(
+            check(big, DevOwnership::Ours(pool_uuid, Uuid::new_v4()), None),
+            Err(DeviceRejection::UnknownToPool)
+        );
+        assert_eq!(
+            check(big, DevOwnership::Ours(other_pool_uuid, dev_uuid), force),
+            Err(DeviceRejection::OtherPool(other_pool_uuid))
+        );
+        assert_eq!(ForceToken::from_user_flag(false), None);
+        assert_eq!(ForceToken::from_user_flag(true), force);
+    }
+
+    #[test]
+    /// Verify that a device with both a Stratis BDA and a foreign signature
+    /// is never admitted, whatever the force token, unless it already
+    /// belongs to the pool, in which case it is left alone.
+    fn test_check_dev_ambiguous() {
+        let pool_uuid = Uuid::new_v4();
+        let other_pool_uuid = Uuid::new_v4();
+        let dev_uuid = Uuid::new_v4();
+        let owned_devs: HashSet<DevUuid> = [dev_uuid].iter().cloned().collect();
+        let check = |ownership: DevOwnership, force| {
+            check_dev(
+                MIN_DEV_SIZE,
+                MIN_MDA_SECTORS,
+                &ownership,
+                pool_uuid,
+                force,
+                &owned_devs,
+            )
+        };
+        let ambiguous = |uuid| DevOwnership::Ambiguous(uuid, Uuid::new_v4(), "xfs".into());
+
+        for force in &[None, Some(ForceToken::overwrite_foreign())] {
+            assert_eq!(
+                check(ambiguous(other_pool_uuid), *force),
+                Err(DeviceRejection::Ambiguous(other_pool_uuid, "xfs".into()))
+            );
+            assert_eq!(
+                check(ambiguous(pool_uuid), *force),
+                Err(DeviceRejection::Ambiguous(pool_uuid, "xfs".into()))
+            );
+        }
+        assert_eq!(
+            check(
+                DevOwnership::Ambiguous(pool_uuid, dev_uuid, "xfs".into()),
+                None
+            ),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    /// Verify that a device large enough in itself is refused if the
+    /// metadata requested would leave too little room for data.
+    fn test_check_dev_room_for_data() {
+        let pool_uuid = Uuid::new_v4();
+        let check = |dev_size, mda_size| {
+            check_dev(
+                dev_size,
+                mda_size,
+                &DevOwnership::Unowned,
+                pool_uuid,
+                None,
+                &HashSet::new(),
+            )
+        };
+
+        let mda_size = Sectors(IEC::Gi);
+        let metadata_size = bda_size(mda_size).bytes();
+        let needed = metadata_size + MIN_DATA_SIZE;
+        assert_eq!(check(needed, mda_size), Ok(true));
+        assert_eq!(
+            check(needed - Bytes(1), mda_size),
+            Err(DeviceRejection::NoRoomForData(needed - Bytes(1), metadata_size))
+        );
+        assert_eq!(check(needed - Bytes(1), MIN_MDA_SECTORS), Ok(true));
+
+        let err_msg = DeviceRejection::NoRoomForData(needed - Bytes(1), metadata_size)
+            .to_error(Path::new("/dev/memory0"))
+            .to_string();
+        assert!(err_msg.contains(&metadata_size.to_string()));
+        assert!(err_msg.contains(&MIN_DATA_SIZE.to_string()));
+    }
+
+    #[test]
+    /// Verify that exactly the devices with a mounted filesystem are
+    /// refused.
+    fn test_check_mounted() {
+        let dev = Device { major: 8, minor: 0 };
+        let mounted: HashSet<Device> = [dev].iter().cloned().collect();
+        let other = Device { major: 8, minor: 16 };
+
+        assert_eq!(check_mounted(other, &mounted), Ok(()));
+        assert_eq!(check_mounted(dev, &mounted), Err(DeviceRejection::Mounted));
+    }
+
+    #[test]
+    /// Verify that each reason for rejecting a device is reported with an
+    /// error of the appropriate kind.
+    fn test_rejection_error_kinds() {
+        let devnode = Path::new("/dev/memory0");
+        let kind = |rejection: &DeviceRejection| match rejection.to_error(devnode) {
+            StratisError::Engine(kind, _) => kind,
+            err => panic!("expected an engine error, got {:?}", err),
+        };
+
+        match kind(&DeviceRejection::Unreadable("no such device".into())) {
+            ErrorEnum::Error => {}
+            kind => panic!("expected a general error, got {:?}", kind),
+        }
+        match kind(&DeviceRejection::ZeroSize) {
+            ErrorEnum::Busy => {}
+            kind => panic!("expected a busy error, got {:?}", kind),
+        }
+        assert!(DeviceRejection::ZeroSize.to_error(devnode).to_string().contains("ready"));
+        for rejection in &[
+            DeviceRejection::TooSmall(Bytes(0)),
+            DeviceRejection::NoRoomForData(Bytes(0), Bytes(0)),
+        ] {
+            match kind(rejection) {
+                ErrorEnum::NotEnoughSpace => {}
+                kind => panic!("expected a not enough space error, got {:?}", kind),
+            }
+        }
+        for rejection in &[
+            DeviceRejection::Signature("xfs".into()),
+            DeviceRejection::OtherPool(Uuid::new_v4()),
+            DeviceRejection::UnknownToPool,
+            DeviceRejection::PartitionInUse(Device { major: 8, minor: 0 }),
+            DeviceRejection::Mounted,
+            DeviceRejection::Ambiguous(Uuid::new_v4(), "xfs".into()),
+        ] {
+            match kind(rejection) {
+                ErrorEnum::PolicyViolation => {}
+                kind => panic!("expected a policy violation error, got {:?}", kind),
+            }
+        }
+    }
+
+    /// Serialized pool metadata recording the given data devices, and a
+    /// data segment on each of the devices in data_segments.
+    fn pool_metadata(data_devs: Vec<DevUuid>, data_segments: Vec<DevUuid>) -> Vec<u8> {
+        let pool_save = PoolSave {
+            name: "pool".into(),
+            backstore: BackstoreSave {
+                cache_devs: None,
+                cache_segments: None,
+                alloc_chunk: None,
+                min_devices: None,
+                data_devs: data_devs.into_iter().map(blockdev_save).collect(),
+                data_segments: data_segments
+                    .into_iter()
+                    .map(|uuid| (uuid, Sectors(0), Sectors(1)))
+                    .collect(),
+                meta_segments: None,
+            },
+            flex_devs: FlexDevsSave {
+                meta_dev: vec![],
+                thin_meta_dev: vec![],
+                thin_data_dev: vec![],
+                thin_meta_dev_spare: vec![],
+            },
+            thinpool_dev: ThinPoolDevSave {
+                data_block_size: Sectors(2048),
+            },
+        };
+        serde_json::to_vec(&pool_save).unwrap()
+    }
+
+    #[test]
+    /// Verify that metadata that does not describe the devices managed is
+    /// rejected.
+    fn test_validate_metadata() {
+        let mut mgr = BlockDevMgr::new(vec![], None);
+        let uuid = Uuid::new_v4();
+
+        assert!(mgr.validate_metadata(&pool_metadata(vec![], vec![])).is_ok());
+        assert!(mgr.validate_metadata(b"{\"name\": \"pool\"}").is_err());
+        assert!(mgr.validate_metadata(&pool_metadata(vec![uuid], vec![])).is_err());
+        assert!(mgr.validate_metadata(&pool_metadata(vec![], vec![uuid])).is_err());
+
+        // Invalid metadata is rejected before any attempt to write it.
+        match mgr.save_state(b"garbage") {
+            Err(StratisError::Engine(ErrorEnum::Invalid, _)) => (),
+            res => panic!("expected an invalid metadata error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    /// Verify that the bound on concurrent writes must be positive, and
+    /// that an empty BlockDevMgr has the minimum MDA size.
+    fn test_max_concurrent_writes() {
+        let mut mgr = BlockDevMgr::new(vec![], None);
+        assert_eq!(mgr.mda_size(), MIN_MDA_SECTORS);
+
+        assert_eq!(mgr.max_concurrent_writes, DEFAULT_MAX_CONCURRENT_WRITES);
+        assert!(mgr.set_max_concurrent_writes(0).is_err());
+        mgr.set_max_concurrent_writes(2).unwrap();
+        assert_eq!(mgr.max_concurrent_writes, 2);
+    }
+
+    #[test]
+    /// Verify that the running total of available space agrees with the
+    /// space available on the blockdevs as space is allocated.
+    fn test_avail_space_total() {
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::memory_backed(pool_uuid, &[Sectors(IEC::Mi); 4]);
+        assert_eq!(mgr.avail_space, mgr.sum_avail_space());
+
+        mgr.alloc_space(&[Sectors(IEC::Mi), Sectors(100)]).unwrap();
+        assert_eq!(mgr.avail_space, mgr.sum_avail_space());
+    }
+
+    #[test]
+    /// Verify that several sizes requested together are each given their
+    /// own segments, and that if they can not all be satisfied, none is.
+    fn test_alloc_space_multiple() {
+        let mut mgr = BlockDevMgr::memory_backed(Uuid::new_v4(), &[Sectors(IEC::Mi); 2]);
+        let avail = mgr.avail_space();
+
+        let sizes = [Sectors(IEC::Ki), avail - Sectors(2 * IEC::Ki), Sectors(IEC::Ki)];
+        assert!(mgr.alloc_space(&[sizes[0], avail]).is_none());
+        assert_eq!(mgr.avail_space(), avail);
+
+        let lists = mgr.alloc_space(&sizes).unwrap();
+        assert_eq!(lists.len(), sizes.len());
+        for (list, &size) in lists.iter().zip(sizes.iter()) {
+            let total: Sectors = list.iter().map(|bseg| bseg.segment.length).sum();
+            assert_eq!(total, size);
+        }
+        assert_eq!(lists[1].len(), 2);
+        assert_eq!(mgr.avail_space(), Sectors(0));
+    }
+
+    #[test]
+    /// Verify that the blockdevs are recorded in order of UUID, regardless
+    /// of the order in which the BlockDevMgr holds them.
+    fn test_record_order() {
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::memory_backed(pool_uuid, &[Sectors(IEC::Mi); 4]);
+        let saves = mgr.record();
+        let mut uuids: Vec<DevUuid> = mgr.block_devs.iter().map(|bd| bd.uuid()).collect();
+        uuids.sort();
+        assert_eq!(saves.iter().map(|save| save.uuid).collect::<Vec<_>>(), uuids);
+
+        mgr.block_devs.reverse();
+        assert_eq!(mgr.record(), saves);
+    }
+
+    #[test]
+    /// Verify that plan_request() returns an error rather than panicking if
+    /// the sources can not grant all that is needed, and leaves the ranges
+    /// taken for the caller to give back.
+    fn test_plan_request_short() {
+        let mut sources = vec![Sectors(3), Sectors(4)];
+        let request = |source: &mut Sectors, wanted: Sectors| {
+            let gotten = min(*source, wanted);
+            *source -= gotten;
+            (gotten, vec![(Sectors(0), gotten)])
+        };
+
+        let mut ranges = Vec::new();
+        assert!(plan_request(&mut sources, Sectors(5), &mut ranges, request).is_ok());
+        assert_eq!(
+            ranges,
+            vec![(0, Sectors(0), Sectors(3)), (1, Sectors(0), Sectors(2))]
+        );
+
+        let mut ranges = Vec::new();
+        match plan_request(&mut sources, Sectors(5), &mut ranges, request) {
+            Err(StratisError::Engine(ErrorEnum::NotEnoughSpace, _)) => {}
+            res => panic!("expected a not enough space error, got {:?}", res),
+        }
+        assert_eq!(
+            ranges,
+            vec![(0, Sectors(0), Sectors(0)), (1, Sectors(0), Sectors(2))]
+        );
+    }
+
+    #[test]
+    /// Verify that metadata_redundancy() counts only the blockdevs that
+    /// hold an intact copy of the current metadata.
+    fn test_metadata_redundancy() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let mut mgr =
+            BlockDevMgr::file_backed(Uuid::new_v4(), tmp_dir.path(), &[Sectors(IEC::Mi); 4]);
+        let uuids: Vec<DevUuid> = mgr.block_devs.iter().map(|bd| bd.uuid()).collect();
+        assert_eq!(mgr.metadata_redundancy(), 0);
+
+        // The last blockdev is left with a stale copy, by saving the
+        // metadata again while it is out of the pool.
+        mgr.save_state(&pool_metadata(uuids.clone(), vec![])).unwrap();
+        let stale = mgr.block_devs.pop().unwrap();
+        mgr.save_state(&pool_metadata(uuids[..3].to_vec(), vec![uuids[0]])).unwrap();
+        mgr.block_devs.push(stale);
+        assert_eq!(mgr.metadata_redundancy(), 2);
+
+        let slot = last_written_region(&mgr.get_blockdev_by_uuid(uuids[0]).unwrap().devnode);
+        mgr.corrupt_slot(uuids[0], slot).unwrap();
+        mgr.corrupt_slot(uuids[0], slot + 2).unwrap();
+        assert_eq!(mgr.metadata_redundancy(), 1);
+    }
+
+    #[test]
+    /// Verify that the devices that are already blockdevs are removed from
+    /// a DeviceSet, and that the others are kept.
+    fn test_remove_known() {
+        let mgr = BlockDevMgr::memory_backed(Uuid::new_v4(), &[Sectors(IEC::Mi); 2]);
+        let known = *mgr.block_devs[1].device();
+        let unknown = Device {
+            major: 7,
+            minor: 0,
+        };
+        let mut device_set = DeviceSet {
+            devices: [(known, Path::new("/dev/memory1")), (unknown, Path::new("/dev/loop0"))]
+                .iter()
+                .cloned()
+                .collect(),
+        };
+
+        device_set.remove_known(|dev| mgr.get_blockdev_by_device(dev).is_some());
+        assert_eq!(device_set.devices.keys().collect::<Vec<_>>(), vec![&unknown]);
+        device_set.remove_known(|dev| mgr.get_blockdev_by_device(dev).is_some());
+        assert_eq!(device_set.devices.keys().collect::<Vec<_>>(), vec![&unknown]);
+    }
+
+    #[test]
+    /// Verify that a BlockDevMgrConfig is applied to the BlockDevMgr made
+    /// from it, and that inconsistent options are rejected.
+    fn test_config() {
+        let mgr = BlockDevMgr::new_with_config(vec![], None, BlockDevMgrConfig::default()).unwrap();
+        assert_eq!(mgr.max_concurrent_writes, DEFAULT_MAX_CONCURRENT_WRITES);
+        assert_eq!(mgr.alloc_chunk(), Sectors(1));
+        assert_eq!(mgr.min_devices(), 1);
+
+        let config = BlockDevMgrConfig {
+            max_concurrent_writes: 2,
+            alloc_chunk: Sectors(8),
+            ..Default::default()
+        };
+        let mgr = BlockDevMgr::new_with_config(vec![], None, config).unwrap();
+        assert_eq!(mgr.max_concurrent_writes, 2);
+        assert_eq!(mgr.alloc_chunk(), Sectors(8));
+
+        for config in &[
+            BlockDevMgrConfig {
+                max_concurrent_writes: 0,
+                ..Default::default()
+            },
+            BlockDevMgrConfig {
+                alloc_chunk: Sectors(0),
+                ..Default::default()
+            },
+        ] {
+            match BlockDevMgr::new_with_config(vec![], None, config.clone()) {
+                Err(StratisError::Engine(ErrorEnum::Invalid, _)) => {}
+                res => panic!("expected an invalid configuration error, got {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    /// Verify that a pool is not made with fewer paths than the minimum
+    /// number of devices, without any device being opened.
+    fn test_min_devices_paths() {
+        let config = BlockDevMgrConfig {
+            min_devices: 2,
+            ..Default::default()
+        };
+        match BlockDevMgr::initialize_with_config(
+            Uuid::new_v4(),
+            &[Path::new("/dev/nonexistent")],
+            MIN_MDA_SECTORS,
+            None,
+            config,
+        ) {
+            Err(StratisError::Engine(ErrorEnum::PolicyViolation, _)) => {}
+            res => panic!("expected a policy violation error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    /// Verify that specifying no devices at all is an error.
+    fn test_no_paths() {
+        let assert_no_devices_err = |res: StratisResult<()>| match res {
+            Err(StratisError::Engine(ErrorEnum::Invalid, ref msg))
+                if msg == "no devices specified" => {}
+            res => panic!("expected a no devices error, got {:?}", res),
+        };
+
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::new(vec![], None);
+        assert_no_devices_err(
+            BlockDevMgr::initialize(pool_uuid, &[], MIN_MDA_SECTORS, None).map(|_| ()),
+        );
+        assert_no_devices_err(mgr.add(pool_uuid, &[], None).map(|_| ()));
+        assert!(mgr.blockdevs().is_empty());
+    }
+
+    #[test]
+    /// Verify that devices are refused before they are examined if the
+    /// pool's metadata would not fit in their MDA.
+    fn test_add_metadata_too_large() {
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::memory_backed(pool_uuid, &[Sectors(IEC::Mi)]);
+        let max_len = max_metadata_len(mgr.mda_size());
+
+        mgr.last_metadata = Some((Utc::now(), vec![0; *max_len as usize + 1]));
+        let path = Path::new("/dev/nonexistent");
+        match mgr.add(pool_uuid, &[path], None) {
+            Err(StratisError::Engine(ErrorEnum::NotEnoughSpace, ref msg))
+                if msg.contains("pool metadata") => {}
+            res => panic!("expected a metadata size error, got {:?}", res),
+        }
+
+        // Metadata that just fits is no obstacle
+        mgr.last_metadata = Some((Utc::now(), vec![0; *max_len as usize]));
+        assert!(mgr.check_metadata_fits(mgr.mda_size()).is_ok());
+    }
+
+    #[test]
+    /// Verify that a BlockDevMgr that has been shut down refuses every
+    /// operation that would write to its blockdevs, and is shut down even
+    /// if its blockdevs could not be synced.
+    fn test_shutdown() {
+        let mut mgr = BlockDevMgr::new(vec![], None);
+        assert!(!mgr.shut_down);
+        mgr.shutdown().unwrap();
+        assert!(mgr.shut_down);
+
+        let assert_shut_down_err = |res: StratisResult<()>| match res {
+            Err(StratisError::Engine(ErrorEnum::Invalid, _)) => (),
+            res => panic!("expected an invalid error, got {:?}", res),
+        };
+
+        assert_shut_down_err(mgr.save_state(&[1, 2, 3]));
+        assert_shut_down_err(mgr.add(Uuid::new_v4(), &[], None).map(|_| ()));
+        assert_shut_down_err(mgr.destroy_all());
+        assert!(mgr.alloc_space(&[Sectors(0)]).is_none());
+        assert!(mgr.shutdown().is_ok());
+
+        // The memory backed blockdevs have no device nodes to sync.
+        let mut mgr = BlockDevMgr::memory_backed(Uuid::new_v4(), &[Sectors(IEC::Mi)]);
+        assert!(mgr.shutdown().is_err());
+        assert!(mgr.shut_down);
+        assert!(mgr.alloc_space(&[Sectors(1)]).is_none());
+    }
+
+    /// Verify that initially,
+    /// current_capacity() - metadata_size() = avail_space().
+    /// After 2 Sectors have been allocated, that amount must also be included
+    /// in balance.
+    fn test_blockdevmgr_used(paths: &[&Path]) -> () {
         let mut mgr =
-            BlockDevMgr::initialize(Uuid::new_v4(), paths, MIN_MDA_SECTORS, false).unwrap();
+            BlockDevMgr::initialize(Uuid::new_v4(), paths, MIN_MDA_SECTORS, None).unwrap();
         assert_eq!(
             mgr.avail_space() + mgr.metadata_size(),
             mgr.current_capacity()
@@ -519,6 +2156,15 @@ mod tests {
             mgr.avail_space() + allocated + mgr.metadata_size(),
             mgr.current_capacity()
         );
+
+        let avail_space = mgr.avail_space();
+        mgr.alloc_space(&[avail_space]).unwrap();
+        mgr.check_invariants().unwrap();
+        assert_eq!(mgr.mda_size(), MIN_MDA_SECTORS);
+
+        assert_eq!(mgr.max_allocatable(false), mgr.avail_space());
+        assert!(mgr.max_allocatable(true) <= mgr.max_allocatable(false));
+        assert!(mgr.max_allocatable(true) > Sectors(0));
     }
 
     #[test]
@@ -545,6 +2191,222 @@ mod tests {
         );
     }
 
+    /// Add a key of type user with the given description and payload to the
+    /// keyring of this process, where dm-crypt can find it.
+    fn add_user_key(key_desc: &str, payload: &[u8]) {
+        const KEY_SPEC_PROCESS_KEYRING: nix::libc::c_long = -2;
+        let key_type = CString::new("user").unwrap();
+        let desc = CString::new(key_desc).unwrap();
+        let serial = unsafe {
+            nix::libc::syscall(
+                nix::libc::SYS_add_key,
+                key_type.as_ptr(),
+                desc.as_ptr(),
+                payload.as_ptr(),
+                payload.len(),
+                KEY_SPEC_PROCESS_KEYRING,
+            )
+        };
+        assert!(serial > 0, "failed to add key \"{}\"", key_desc);
+    }
+
+    /// Verify that the blockdevs of an encrypted BlockDevMgr, including
+    /// those added later, are unlocked, that tearing them down removes
+    /// their dm-crypt devices, and that setting them up again from the
+    /// metadata unlocks them again. Verify that a blockdev whose key is
+    /// missing can not be set up, and that the error names the blockdev and
+    /// the key.
+    fn test_encrypted_setup(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+        let pool_uuid = Uuid::new_v4();
+        let key_desc = format!("stratis-test-{}", pool_uuid.simple());
+        add_user_key(&key_desc, &[0x5a; 64]);
+
+        let mut mgr = BlockDevMgr::initialize_encrypted(
+            pool_uuid,
+            paths1,
+            MIN_MDA_SECTORS,
+            None,
+            &key_desc,
+            BlockDevMgrConfig::default(),
+        ).unwrap();
+        mgr.add(pool_uuid, paths2, None).unwrap();
+        assert_eq!(mgr.block_devs.len(), paths.len());
+        let crypt_exists = |bd: &StratBlockDev| {
+            let (name, _) = format_crypt_ids(pool_uuid, bd.uuid());
+            device_exists(get_dm(), &name).unwrap()
+        };
+        for bd in &mgr.block_devs {
+            assert_eq!(bd.key_description(), Some(&key_desc));
+            assert_ne!(bd.data_device(), *bd.device());
+            assert!(crypt_exists(bd));
+        }
+
+        let backstore_save = |data_devs| BackstoreSave {
+            cache_devs: None,
+            cache_segments: None,
+            alloc_chunk: None,
+            min_devices: None,
+            data_devs,
+            data_segments: vec![],
+            meta_segments: None,
+        };
+        let saved = backstore_save(mgr.record());
+        let mut missing_save = backstore_save(mgr.record());
+        mgr.teardown().unwrap();
+        assert!(mgr.block_devs.iter().all(|bd| !crypt_exists(bd)));
+
+        cmd::udev_settle().unwrap();
+        let map = find_all().unwrap();
+        let map = map.get(&pool_uuid).unwrap();
+
+        let missing_key = format!("{}-missing", key_desc);
+        missing_save.data_devs[0].key_description = Some(missing_key.clone());
+        match get_blockdevs(pool_uuid, &missing_save, map) {
+            Err(StratisError::Engine(ErrorEnum::Error, msg)) => {
+                assert!(msg.contains(&missing_key));
+                assert!(msg.contains(&missing_save.data_devs[0].uuid.to_string()));
+            }
+            Err(err) => panic!("expected an error unlocking the blockdev, got {:?}", err),
+            Ok(_) => panic!("expected an error unlocking the blockdev"),
+        }
+
+        let (datadevs, _) = get_blockdevs(pool_uuid, &saved, map).unwrap();
+        assert_eq!(datadevs.len(), paths.len());
+        for bd in &datadevs {
+            assert_eq!(bd.key_description(), Some(&key_desc));
+            assert_ne!(bd.data_device(), *bd.device());
+            assert!(crypt_exists(bd));
+        }
+
+        let mut mgr = BlockDevMgr::new(datadevs, None);
+        mgr.destroy_all().unwrap();
+        assert!(mgr.block_devs.iter().all(|bd| !crypt_exists(bd)));
+    }
+
+    #[test]
+    pub fn loop_test_encrypted_setup() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(2, 3, None),
+            test_encrypted_setup,
+        );
+    }
+
+    #[test]
+    pub fn real_test_encrypted_setup() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2, None, None), test_encrypted_setup);
+    }
+
+    #[test]
+    pub fn travis_test_encrypted_setup() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(2, 3, None),
+            test_encrypted_setup,
+        );
+    }
+
+    /// Verify that the dm-crypt device of each blockdev of an encrypted
+    /// BlockDevMgr has a single crypt target, which spans the whole of the
+    /// blockdev and is keyed from the kernel keyring.
+    fn test_crypt_table(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let key_desc = format!("stratis-test-{}", pool_uuid.simple());
+        add_user_key(&key_desc, &[0x5a; 64]);
+
+        let mut mgr = BlockDevMgr::initialize_encrypted(
+            pool_uuid,
+            paths,
+            MIN_MDA_SECTORS,
+            None,
+            &key_desc,
+            BlockDevMgrConfig::default(),
+        ).unwrap();
+        for bd in &mgr.block_devs {
+            let (name, _) = format_crypt_ids(pool_uuid, bd.uuid());
+            let (_, table) = get_dm()
+                .table_status(
+                    &DevId::Name(&name),
+                    DmOptions::new().set_flags(DmFlags::DM_STATUS_TABLE),
+                )
+                .unwrap();
+            assert_eq!(table.len(), 1);
+            let (start, length, ref target_type, ref params) = table[0];
+            assert_eq!(start, Sectors(0));
+            assert_eq!(length, bd.current_capacity());
+            assert_eq!(target_type.to_string(), "crypt");
+            assert_eq!(
+                *params,
+                format!("aes-xts-plain64 :64:user:{} 0 {} 0", key_desc, bd.device())
+            );
+        }
+        mgr.destroy_all().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_crypt_table() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3, None), test_crypt_table);
+    }
+
+    #[test]
+    pub fn real_test_crypt_table() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1, None, None), test_crypt_table);
+    }
+
+    #[test]
+    pub fn travis_test_crypt_table() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3, None), test_crypt_table);
+    }
+
+    /// Verify that a device with a mounted filesystem is refused even with
+    /// permission to overwrite foreign signatures.
+    fn test_refuse_mounted(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        cmd::create_ext3_fs(paths[0]).unwrap();
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(paths[0]),
+            tmp_dir.path(),
+            Some("ext3"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        ).unwrap();
+
+        let force = ForceToken::overwrite_foreign();
+        match BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, Some(force)) {
+            Err(StratisError::Engine(ErrorEnum::PolicyViolation, _)) => {}
+            Err(err) => panic!("expected a mounted device error, got {:?}", err),
+            Ok(_) => panic!("expected a mounted device error"),
+        }
+
+        umount(tmp_dir.path()).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_refuse_mounted() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(1, 3, None),
+            test_refuse_mounted,
+        );
+    }
+
+    #[test]
+    pub fn real_test_refuse_mounted() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1, None, None), test_refuse_mounted);
+    }
+
+    #[test]
+    pub fn travis_test_refuse_mounted() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(1, 3, None),
+            test_refuse_mounted,
+        );
+    }
+
     /// Verify that it is impossible to initialize a set of disks of which
     /// even one of them has a signature.  Choose the dirty disk randomly.
     /// Verify that force flag allows initialization in the presence of
@@ -556,7 +2418,7 @@ mod tests {
         cmd::udev_settle().unwrap();
 
         let pool_uuid = Uuid::new_v4();
-        assert!(BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).is_err());
+        assert!(BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None).is_err());
         assert!(paths.iter().enumerate().all(|(i, path)| {
             let tmp = if i == index {
                 DevOwnership::Theirs(String::from(""))
@@ -567,7 +2429,8 @@ mod tests {
             usage_equal(&identify(path).unwrap(), &tmp)
         }));
 
-        assert!(BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, true).is_ok());
+        let force = Some(ForceToken::overwrite_foreign());
+        assert!(BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, force).is_ok());
         cmd::udev_settle().unwrap();
 
         assert!(paths.iter().all(|path| {
@@ -618,22 +2481,23 @@ mod tests {
         let uuid = Uuid::new_v4();
         let uuid2 = Uuid::new_v4();
 
-        let mut bd_mgr = BlockDevMgr::initialize(uuid, paths1, MIN_MDA_SECTORS, false).unwrap();
+        let mut bd_mgr = BlockDevMgr::initialize(uuid, paths1, MIN_MDA_SECTORS, None).unwrap();
         cmd::udev_settle().unwrap();
 
-        assert!(BlockDevMgr::initialize(uuid2, paths1, MIN_MDA_SECTORS, false).is_err());
+        assert!(BlockDevMgr::initialize(uuid2, paths1, MIN_MDA_SECTORS, None).is_err());
         // FIXME: this should succeed, but currently it fails, to be extra safe.
         // See: https://github.com/stratis-storage/stratisd/pull/292
-        assert!(BlockDevMgr::initialize(uuid2, paths1, MIN_MDA_SECTORS, true).is_err());
+        let force = Some(ForceToken::overwrite_foreign());
+        assert!(BlockDevMgr::initialize(uuid2, paths1, MIN_MDA_SECTORS, force).is_err());
 
         let original_length = bd_mgr.block_devs.len();
-        assert!(bd_mgr.add(uuid, paths1, false).is_ok());
+        assert!(bd_mgr.add(uuid, paths1, None).unwrap().is_empty());
         assert_eq!(bd_mgr.block_devs.len(), original_length);
 
-        BlockDevMgr::initialize(uuid, paths2, MIN_MDA_SECTORS, false).unwrap();
+        BlockDevMgr::initialize(uuid, paths2, MIN_MDA_SECTORS, None).unwrap();
         cmd::udev_settle().unwrap();
 
-        assert!(bd_mgr.add(uuid, paths2, false).is_err());
+        assert!(bd_mgr.add(uuid, paths2, None).is_err());
     }
 
     #[test]
@@ -660,6 +2524,205 @@ mod tests {
         );
     }
 
+    /// Verify that a pool is not made with fewer devices than the minimum,
+    /// even when enough paths are specified, and that nothing is written to
+    /// the devices if it is not.
+    fn test_min_devices(paths: &[&Path]) {
+        let uuid = Uuid::new_v4();
+        let config = BlockDevMgrConfig {
+            min_devices: paths.len() + 1,
+            ..Default::default()
+        };
+
+        // The same device twice counts once.
+        let mut repeated = paths.to_vec();
+        repeated.push(paths[0]);
+        match BlockDevMgr::initialize_with_config(
+            uuid,
+            &repeated,
+            MIN_MDA_SECTORS,
+            None,
+            config,
+        ) {
+            Err(StratisError::Engine(ErrorEnum::PolicyViolation, _)) => {}
+            res => panic!("expected a policy violation error, got {:?}", res.map(|_| ())),
+        }
+
+        let config = BlockDevMgrConfig {
+            min_devices: paths.len(),
+            ..Default::default()
+        };
+        let bd_mgr =
+            BlockDevMgr::initialize_with_config(uuid, paths, MIN_MDA_SECTORS, None, config)
+                .unwrap();
+        assert_eq!(bd_mgr.blockdevs().len(), paths.len());
+    }
+
+    #[test]
+    pub fn loop_test_min_devices() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(2, 3, None),
+            test_min_devices,
+        );
+    }
+
+    #[test]
+    pub fn real_test_min_devices() {
+        real::test_with_spec(
+            real::DeviceLimits::AtLeast(2, None, None),
+            test_min_devices,
+        );
+    }
+
+    #[test]
+    pub fn travis_test_min_devices() {
+        loopbacked::test_with_spec(
+            loopbacked::DeviceLimits::Range(2, 3, None),
+            test_min_devices,
+        );
+    }
+
+    #[test]
+    /// Verify that metadata is loaded from the copy of its MDA region if the
+    /// region is corrupt, and from the other blockdevs if both are, and that
+    /// a blockdev with both corrupt falls back on the older region.
+    fn test_corrupt_slot() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let pool_uuid = Uuid::new_v4();
+        let mut mgr = BlockDevMgr::file_backed(pool_uuid, tmp_dir.path(), &[Sectors(IEC::Mi); 2]);
+        let uuids: Vec<DevUuid> = mgr.blockdevs().iter().map(|&(uuid, _)| uuid).collect();
+
+        let first = pool_metadata(uuids.clone(), vec![]);
+        let mut pool_save: PoolSave = serde_json::from_slice(&first).unwrap();
+        pool_save.name = "renamed".into();
+        let second = serde_json::to_vec(&pool_save).unwrap();
+        mgr.save_state(&first).unwrap();
+        mgr.save_state(&second).unwrap();
+
+        let devnode = mgr.get_blockdev_by_uuid(uuids[0]).unwrap().devnode.clone();
+        let load = |devnode: &Path| {
+            let mut f = OpenOptions::new().read(true).open(devnode).unwrap();
+            let bda = BDA::load(&mut f).unwrap().unwrap();
+            (bda.last_written_region(), bda.load_state(&mut f).unwrap())
+        };
+        let (slot, metadata) = load(&devnode);
+        assert_eq!(metadata, Some(second.clone()));
+        let slot = slot.unwrap();
+
+        mgr.corrupt_slot(uuids[0], slot).unwrap();
+        assert_eq!(load(&devnode).1, Some(second.clone()));
+
+        mgr.corrupt_slot(uuids[0], slot + 2).unwrap();
+        assert_eq!(load(&devnode).1, Some(first.clone()));
+
+        let devnodes: HashMap<Device, PathBuf> = mgr.block_devs
+            .iter()
+            .map(|bd| (*bd.device(), bd.devnode.clone()))
+            .collect();
+        assert_eq!(get_metadata(pool_uuid, &devnodes).unwrap(), Some(pool_save));
+
+        match mgr.corrupt_slot(uuids[0], 4) {
+            Err(StratisError::Engine(ErrorEnum::Invalid, _)) => {}
+            res => panic!("expected an invalid slot error, got {:?}", res),
+        }
+    }
+
+    /// The index of the MDA region to which metadata was last written on
+    /// the device at devnode.
+    fn last_written_region(devnode: &Path) -> usize {
+        let mut f = OpenOptions::new().read(true).open(devnode).unwrap();
+        BDA::load(&mut f)
+            .unwrap()
+            .unwrap()
+            .last_written_region()
+            .unwrap()
+    }
+
+    #[test]
+    /// Verify that the newest metadata that can be read back from the
+    /// blockdevs is taken to be the last saved, so that the room for the
+    /// metadata of a pool that has been set up again is checked.
+    fn test_load_last_metadata() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let mut mgr =
+            BlockDevMgr::file_backed(Uuid::new_v4(), tmp_dir.path(), &[Sectors(IEC::Mi); 2]);
+        let uuids: Vec<DevUuid> = mgr.block_devs.iter().map(|bd| bd.uuid()).collect();
+        mgr.load_last_metadata();
+        assert!(mgr.last_metadata().is_none());
+
+        // The last blockdev is left with a stale copy, by saving the
+        // metadata again while it is out of the pool.
+        let second = pool_metadata(vec![uuids[0]], vec![]);
+        mgr.save_state(&pool_metadata(uuids.clone(), vec![])).unwrap();
+        let stale = mgr.block_devs.pop().unwrap();
+        mgr.save_state(&second).unwrap();
+        mgr.block_devs.push(stale);
+        let saved_time = *mgr.last_metadata().unwrap().0;
+
+        // Set the blockdevs up again, as setup does, from their devnodes.
+        let block_devs = mgr.block_devs
+            .iter()
+            .enumerate()
+            .map(|(i, bd)| {
+                let mut f = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&bd.devnode)
+                    .unwrap();
+                StratBlockDev::new(
+                    Device {
+                        major: 8,
+                        minor: 16 * i as u32,
+                    },
+                    bd.devnode.clone(),
+                    BDA::load(&mut f).unwrap().unwrap(),
+                    &[],
+                    None,
+                    None,
+                ).unwrap()
+            })
+            .collect();
+        let mut set_up = BlockDevMgr::new(block_devs, None);
+        assert!(set_up.last_metadata().is_none());
+        set_up.load_last_metadata();
+        assert_eq!(
+            set_up.last_metadata(),
+            Some((&saved_time, second.as_slice()))
+        );
+        assert_eq!(set_up.last_update_time, Some(saved_time));
+    }
+
+    #[test]
+    /// Verify that the metadata is saved to every blockdev however few of
+    /// the writes may be in progress at once.
+    fn test_save_state_concurrency() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("stratis_testing")
+            .tempdir()
+            .unwrap();
+        let mut mgr =
+            BlockDevMgr::file_backed(Uuid::new_v4(), tmp_dir.path(), &[Sectors(IEC::Mi); 3]);
+        let uuids: Vec<DevUuid> = mgr.block_devs.iter().map(|bd| bd.uuid()).collect();
+        let first = pool_metadata(uuids.clone(), vec![]);
+        let mut pool_save: PoolSave = serde_json::from_slice(&first).unwrap();
+        pool_save.name = "renamed".into();
+        let second = serde_json::to_vec(&pool_save).unwrap();
+
+        for &(max, metadata) in &[(1, &first), (2, &second)] {
+            mgr.set_max_concurrent_writes(max).unwrap();
+            mgr.save_state(metadata).unwrap();
+            for bd in &mgr.block_devs {
+                assert_eq!(bd.load_state().unwrap().as_ref(), Some(metadata));
+            }
+        }
+    }
+
     /// Verify that find_all function locates and assigns pools appropriately.
     /// 1. Split available paths into 2 discrete sets.
     /// 2. Initialize the block devices in the first set with a pool uuid.
@@ -676,7 +2739,7 @@ mod tests {
         let (paths1, paths2) = paths.split_at(paths.len() / 2);
 
         let uuid1 = Uuid::new_v4();
-        BlockDevMgr::initialize(uuid1, paths1, MIN_MDA_SECTORS, false).unwrap();
+        BlockDevMgr::initialize(uuid1, paths1, MIN_MDA_SECTORS, None).unwrap();
 
         cmd::udev_settle().unwrap();
         let pools = find_all().unwrap();
@@ -686,7 +2749,7 @@ mod tests {
         assert_eq!(devices.len(), paths1.len());
 
         let uuid2 = Uuid::new_v4();
-        BlockDevMgr::initialize(uuid2, paths2, MIN_MDA_SECTORS, false).unwrap();
+        BlockDevMgr::initialize(uuid2, paths2, MIN_MDA_SECTORS, None).unwrap();
 
         cmd::udev_settle().unwrap();
         let pools = find_all().unwrap();
@@ -727,7 +2790,7 @@ mod tests {
     /// them releases all.
     fn test_ownership(paths: &[&Path]) -> () {
         let pool_uuid = Uuid::new_v4();
-        let mut bd_mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+        let mut bd_mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None).unwrap();
 
         cmd::udev_settle().unwrap();
 
@@ -13,7 +13,9 @@ use stratis::{ErrorEnum, StratisError, StratisResult};
 use super::super::super::types::{BlockDevTier, DevUuid, PoolUuid};
 
 use super::blockdev::StratBlockDev;
-use super::blockdevmgr::{coalesce_blkdevsegs, BlkDevSegment, BlockDevMgr, Segment};
+use super::blockdevmgr::{
+    coalesce_blkdevsegs, BlkDevSegment, BlockDevMgr, ForceToken, Segment,
+};
 
 /// Handles the cache devices.
 #[derive(Debug)]
@@ -36,6 +38,8 @@ impl CacheTier {
         cache_segments: &[(DevUuid, Sectors, Sectors)],
         meta_segments: &[(DevUuid, Sectors, Sectors)],
     ) -> StratisResult<CacheTier> {
+        block_mgr.check_unique_uuids()?;
+
         if block_mgr.avail_space() != Sectors(0) {
             let err_msg = format!(
                 "{} unallocated to device; probable metadata corruption",
@@ -89,7 +93,7 @@ impl CacheTier {
         &mut self,
         pool_uuid: PoolUuid,
         paths: &[&Path],
-        force: bool,
+        force: Option<ForceToken>,
     ) -> StratisResult<(Vec<DevUuid>, (bool, bool))> {
         let uuids = self.block_mgr.add(pool_uuid, paths, force)?;
 
@@ -139,6 +143,12 @@ impl CacheTier {
         self.block_mgr.destroy_all()
     }
 
+    /// Tear down the dm-crypt devices of the blockdevs in this tier.
+    #[cfg(test)]
+    pub fn teardown(&mut self) -> StratisResult<()> {
+        self.block_mgr.teardown()
+    }
+
     /// Get all the blockdevs belonging to this tier.
     pub fn blockdevs(&self) -> Vec<(DevUuid, &StratBlockDev)> {
         self.block_mgr.blockdevs()
@@ -187,7 +197,7 @@ mod tests {
 
         let pool_uuid = Uuid::new_v4();
 
-        let mgr = BlockDevMgr::initialize(pool_uuid, paths1, MIN_MDA_SECTORS, false).unwrap();
+        let mgr = BlockDevMgr::initialize(pool_uuid, paths1, MIN_MDA_SECTORS, None).unwrap();
 
         let mut cache_tier = CacheTier::new(mgr);
 
@@ -213,7 +223,7 @@ mod tests {
             capacity + cache_metadata_size
         );
 
-        let (_, (cache, meta)) = cache_tier.add(pool_uuid, paths2, false).unwrap();
+        let (_, (cache, meta)) = cache_tier.add(pool_uuid, paths2, None).unwrap();
         // TODO: Ultimately, it should be the case that meta can be true.
         assert!(cache);
         assert!(!meta);
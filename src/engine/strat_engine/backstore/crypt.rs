@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Code to encrypt the data on a blockdev with a dm-crypt mapping.
+//
+// The mapping spans the whole device, so that the offsets of segments
+// allocated on the blockdev are the same whether or not it is encrypted;
+// only the device through which the segments are accessed differs. The
+// sectors that hold the Stratis metadata are never allocated, so they are
+// never written through the mapping, and the metadata remains readable on
+// the device itself, which is how the device is identified before it is
+// unlocked.
+
+use devicemapper::{device_exists, DevId, Device, DmOptions, Sectors, TargetTypeBuf};
+
+use stratis::{ErrorEnum, StratisError, StratisResult};
+
+use super::super::super::types::{DevUuid, PoolUuid};
+
+use super::super::dm::get_dm;
+use super::super::dmnames::format_crypt_ids;
+
+/// The cipher with which blockdevs are encrypted.
+const CIPHER: &str = "aes-xts-plain64";
+
+/// The size in bytes of the key the cipher requires.
+const KEY_SIZE: usize = 64;
+
+/// Verify that key_desc could describe a key in the kernel keyring, as
+/// dm-crypt expects.
+pub fn validate_key_description(key_desc: &str) -> StratisResult<()> {
+    if key_desc.is_empty() || key_desc.contains(char::is_whitespace) {
+        let err_msg = format!("\"{}\" is not a valid key description", key_desc);
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    Ok(())
+}
+
+/// The parameters of the crypt target that maps device, encrypted with the
+/// key of type user with description key_desc in the kernel keyring.
+fn crypt_params(device: Device, key_desc: &str) -> String {
+    format!("{} :{}:user:{} 0 {} 0", CIPHER, KEY_SIZE, key_desc, device)
+}
+
+/// Set up the dm-crypt mapping of the first size sectors of device,
+/// the blockdev with UUID dev_uuid in the pool with UUID pool_uuid, if it is
+/// not already set up. The key, described by key_desc, must already be in
+/// the kernel keyring.
+/// Returns the device through which the data is accessed.
+pub fn setup_crypt(
+    pool_uuid: PoolUuid,
+    dev_uuid: DevUuid,
+    device: Device,
+    size: Sectors,
+    key_desc: &str,
+) -> StratisResult<Device> {
+    validate_key_description(key_desc)?;
+    let dm = get_dm();
+    let (name, uuid) = format_crypt_ids(pool_uuid, dev_uuid);
+    let id = DevId::Name(&name);
+    if device_exists(dm, &name)? {
+        return Ok(dm.device_info(&id)?.device());
+    }
+
+    dm.device_create(&name, Some(&uuid), &DmOptions::new())?;
+    let table = [(
+        Sectors(0),
+        size,
+        TargetTypeBuf::new("crypt".into()).expect("\"crypt\" is a valid target type"),
+        crypt_params(device, key_desc),
+    )];
+    if let Err(err) = dm.table_load(&id, &table)
+        .and_then(|_| dm.device_suspend(&id, &DmOptions::new()))
+    {
+        // TODO: check the return value and update state machine on failure
+        let _ = dm.device_remove(&id, &DmOptions::new());
+        return Err(err.into());
+    }
+    Ok(dm.device_info(&id)?.device())
+}
+
+/// Tear down the dm-crypt mapping of the blockdev with UUID dev_uuid in the
+/// pool with UUID pool_uuid, if it is set up.
+pub fn teardown_crypt(pool_uuid: PoolUuid, dev_uuid: DevUuid) -> StratisResult<()> {
+    let dm = get_dm();
+    let (name, _) = format_crypt_ids(pool_uuid, dev_uuid);
+    if device_exists(dm, &name)? {
+        dm.device_remove(&DevId::Name(&name), &DmOptions::new())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the crypt target refers to the key in the keyring and to
+    /// the whole of the device.
+    fn test_crypt_params() {
+        let device = Device { major: 8, minor: 16 };
+        assert_eq!(
+            crypt_params(device, "stratis-pool1"),
+            "aes-xts-plain64 :64:user:stratis-pool1 0 8:16 0"
+        );
+    }
+
+    #[test]
+    /// Verify that a key description that is empty or that would be split
+    /// when the table is parsed is refused.
+    fn test_validate_key_description() {
+        assert!(validate_key_description("stratis-pool1").is_ok());
+        assert!(validate_key_description("").is_err());
+        assert!(validate_key_description("stratis pool1").is_err());
+    }
+}
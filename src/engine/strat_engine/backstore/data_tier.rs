@@ -4,6 +4,7 @@
 
 // Code to handle the backing store of a pool.
 
+use std::collections::HashMap;
 use std::path::Path;
 
 use devicemapper::Sectors;
@@ -12,8 +13,71 @@ use stratis::{ErrorEnum, StratisError, StratisResult};
 
 use super::super::super::types::{BlockDevTier, DevUuid, PoolUuid};
 
+use super::super::serde_structs::Recordable;
+
 use super::blockdev::StratBlockDev;
-use super::blockdevmgr::{coalesce_blkdevsegs, BlkDevSegment, BlockDevMgr, Segment};
+use super::blockdevmgr::{coalesce_blkdevsegs, BlkDevSegment, BlockDevMgr, ForceToken, Segment};
+
+/// A size of the data tier before and after an operation that may have
+/// changed it, returned by the operation itself so that the caller need
+/// not query the size before and after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityDelta {
+    pub before: Sectors,
+    pub after: Sectors,
+}
+
+impl CapacityDelta {
+    /// The number of sectors by which the size grew, or 0 if it did not.
+    pub fn grown(&self) -> Sectors {
+        if self.after > self.before {
+            self.after - self.before
+        } else {
+            Sectors(0)
+        }
+    }
+}
+
+/// Verify that every segment lies within the part of its blockdev that
+/// follows the Stratis metadata, and that no two segments on the same
+/// blockdev overlap.
+fn validate_segments(block_mgr: &BlockDevMgr, segments: &[BlkDevSegment]) -> StratisResult<()> {
+    let mut by_dev: HashMap<DevUuid, Vec<(Sectors, Sectors)>> = HashMap::new();
+    for bseg in segments {
+        let bd = block_mgr
+            .get_blockdev_by_uuid(bseg.uuid)
+            .expect("every segment was mapped to a blockdev");
+        let (start, length) = (bseg.segment.start, bseg.segment.length);
+        if start < bd.metadata_size() || start + length > bd.current_capacity() {
+            let err_msg = format!(
+                "segment ({}, {}) lies outside sectors {} to {} of blockdev {} ({})",
+                start,
+                length,
+                bd.metadata_size(),
+                bd.current_capacity(),
+                bseg.uuid,
+                bd.devnode.display()
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        by_dev.entry(bseg.uuid).or_default().push((start, length));
+    }
+
+    for (uuid, mut ranges) in by_dev {
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            let ((start, length), (next_start, next_length)) = (pair[0], pair[1]);
+            if start + length > next_start {
+                let err_msg = format!(
+                    "segments ({}, {}) and ({}, {}) on blockdev {} overlap",
+                    start, length, next_start, next_length, uuid
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+    }
+    Ok(())
+}
 
 /// Handles the lowest level, base layer of this tier.
 #[derive(Debug)]
@@ -27,10 +91,17 @@ pub struct DataTier {
 impl DataTier {
     /// Setup a previously existing data layer from the block_mgr and
     /// previously allocated segments.
+    /// Returns an error if a segment lies outside the part of its blockdev
+    /// available to upper layers, or if two segments overlap, since either
+    /// means that the metadata is corrupt.
+    /// Consecutive segments that are adjacent on the same blockdev are
+    /// merged, which leaves the mapping unchanged but shortens the table.
     pub fn setup(
         block_mgr: BlockDevMgr,
         segments: &[(DevUuid, Sectors, Sectors)],
     ) -> StratisResult<DataTier> {
+        block_mgr.check_unique_uuids()?;
+
         let uuid_to_devno = block_mgr.uuid_to_devno();
         let mapper = |triple: &(DevUuid, Sectors, Sectors)| -> StratisResult<BlkDevSegment> {
             let device = uuid_to_devno(triple.0).ok_or_else(|| {
@@ -48,11 +119,14 @@ impl DataTier {
             .iter()
             .map(&mapper)
             .collect::<StratisResult<Vec<_>>>()?;
+        validate_segments(&block_mgr, &segments)?;
 
-        Ok(DataTier {
+        let mut data_tier = DataTier {
             block_mgr,
             segments,
-        })
+        };
+        data_tier.coalesce();
+        Ok(data_tier)
     }
 
     /// Setup a new DataTier struct from the block_mgr.
@@ -68,40 +142,97 @@ impl DataTier {
     }
 
     /// Add the given paths to self. Return UUIDs of the new blockdevs
-    /// corresponding to the specified paths.
+    /// corresponding to the specified paths, and the current_capacity()
+    /// of the data tier before and after they were added.
     /// WARNING: metadata changing event
     pub fn add(
         &mut self,
         pool_uuid: PoolUuid,
         paths: &[&Path],
-        force: bool,
-    ) -> StratisResult<Vec<DevUuid>> {
-        self.block_mgr.add(pool_uuid, paths, force)
+        force: Option<ForceToken>,
+    ) -> StratisResult<(Vec<DevUuid>, CapacityDelta)> {
+        let before = self.current_capacity();
+        let uuids = self.block_mgr.add(pool_uuid, paths, force)?;
+        let delta = CapacityDelta {
+            before,
+            after: self.current_capacity(),
+        };
+        Ok((uuids, delta))
     }
 
     /// Allocate at least request sectors from unallocated segments in
-    /// block devices belonging to the data tier. Return true if requested
-    /// amount or more was allocated, otherwise, false.
-    pub fn alloc(&mut self, request: Sectors) -> bool {
-        match self.block_mgr.alloc_space(&[request]) {
+    /// block devices belonging to the data tier. Return the capacity() of
+    /// the data tier before and after the allocation; it grows by 0 if the
+    /// request could not be satisfied, and otherwise by at least the amount
+    /// requested. The request is rounded up to a whole number of the
+    /// block_mgr's allocation chunks, and fails if the rounded request can
+    /// not be satisfied.
+    /// The segments allocated, and the effect of coalescing them with the
+    /// existing segments, are logged at trace level.
+    pub fn alloc(&mut self, request: Sectors) -> CapacityDelta {
+        let before = self.capacity();
+        let allocation = self.block_mgr
+            .round_to_chunk(request)
+            .and_then(|rounded| self.block_mgr.alloc_space(&[rounded]));
+        match allocation {
             Some(segments) => {
-                self.segments = coalesce_blkdevsegs(
-                    &self.segments,
-                    &segments
-                        .iter()
-                        .flat_map(|s| s.iter())
-                        .cloned()
-                        .collect::<Vec<_>>(),
+                let segments = segments
+                    .iter()
+                    .flat_map(|s| s.iter())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let allocated = segments
+                    .iter()
+                    .map(|bseg| bseg.segment.length)
+                    .sum::<Sectors>();
+                assert!(allocated >= request);
+                let coalesced = coalesce_blkdevsegs(&self.segments, &segments);
+                let unchanged = self.segments.len().saturating_sub(1);
+                trace!(
+                    "data tier allocated {} for request {}: segments {:?} coalesced with \
+                     last existing segment into {:?}, {} merged",
+                    allocated,
+                    request,
+                    segments,
+                    &coalesced[unchanged..],
+                    self.segments.len() + segments.len() - coalesced.len()
                 );
-                true
+                self.segments = coalesced;
+                CapacityDelta {
+                    before,
+                    after: before + allocated,
+                }
             }
-            None => false,
+            None => CapacityDelta {
+                before,
+                after: before,
+            },
         }
     }
 
+    /// Merge every pair of consecutive segments that are adjacent on the
+    /// same blockdev into a single segment. The order of the segments, and
+    /// therefore the mapping to the upper device, is unchanged.
+    /// Postcondition: no two consecutive segments in self.segments could be
+    /// merged.
+    pub fn coalesce(&mut self) {
+        let mut segments: Vec<BlkDevSegment> = Vec::with_capacity(self.segments.len());
+        for bseg in self.segments.drain(..) {
+            if let Some(last) = segments.last_mut() {
+                if last.uuid == bseg.uuid
+                    && last.segment.start + last.segment.length == bseg.segment.start
+                {
+                    last.segment.length += bseg.segment.length;
+                    continue;
+                }
+            }
+            segments.push(bseg);
+        }
+        self.segments = segments;
+    }
+
     /// The sum of the lengths of all the sectors that have been mapped to an
     /// upper device.
-    #[cfg(test)]
     pub fn capacity(&self) -> Sectors {
         self.segments
             .iter()
@@ -109,6 +240,12 @@ impl DataTier {
             .sum::<Sectors>()
     }
 
+    /// The (uuid, start, length) triples of self.segments, in order, as
+    /// they are recorded in the metadata and as setup() takes them.
+    pub fn record_segments(&self) -> Vec<(DevUuid, Sectors, Sectors)> {
+        self.segments.record()
+    }
+
     /// The total size of all the blockdevs combined
     pub fn current_capacity(&self) -> Sectors {
         self.block_mgr.current_capacity()
@@ -124,6 +261,12 @@ impl DataTier {
         self.block_mgr.destroy_all()
     }
 
+    /// Tear down the dm-crypt devices of the blockdevs in this tier.
+    #[cfg(test)]
+    pub fn teardown(&mut self) -> StratisResult<()> {
+        self.block_mgr.teardown()
+    }
+
     /// Save the given state to the devices. This action bypasses the DM
     /// device entirely.
     pub fn save_state(&mut self, metadata: &[u8]) -> StratisResult<()> {
@@ -162,12 +305,222 @@ mod tests {
 
     use uuid::Uuid;
 
+    use devicemapper::{Device, IEC};
+
     use super::super::super::tests::{loopbacked, real};
 
+    use super::super::blockdevmgr::map_to_dm;
     use super::super::metadata::MIN_MDA_SECTORS;
 
     use super::*;
 
+    #[test]
+    /// Verify that coalesce() merges exactly those consecutive segments
+    /// that are adjacent on the same device, and preserves their order.
+    fn test_coalesce() {
+        let uuid1 = Uuid::new_v4();
+        let uuid2 = Uuid::new_v4();
+        let dev1 = Device { major: 8, minor: 0 };
+        let dev2 = Device { major: 8, minor: 16 };
+        let bseg = |uuid, device, start, length| {
+            BlkDevSegment::new(uuid, Segment::new(device, Sectors(start), Sectors(length)))
+        };
+
+        let mut data_tier = DataTier::new(BlockDevMgr::new(vec![], None));
+        data_tier.segments = vec![
+            bseg(uuid1, dev1, 0, 10),
+            bseg(uuid1, dev1, 10, 10),
+            bseg(uuid1, dev1, 20, 5),
+            bseg(uuid2, dev2, 25, 5),
+            bseg(uuid2, dev2, 40, 5),
+            bseg(uuid2, dev2, 45, 5),
+            bseg(uuid1, dev1, 25, 5),
+        ];
+        let capacity = data_tier.capacity();
+
+        data_tier.coalesce();
+
+        assert_eq!(
+            data_tier.segments.record(),
+            vec![
+                (uuid1, Sectors(0), Sectors(25)),
+                (uuid2, Sectors(25), Sectors(5)),
+                (uuid2, Sectors(40), Sectors(10)),
+                (uuid1, Sectors(25), Sectors(5)),
+            ]
+        );
+        assert_eq!(data_tier.capacity(), capacity);
+
+        data_tier.coalesce();
+        assert_eq!(data_tier.segments.len(), 4);
+    }
+
+    #[test]
+    /// Verify that allocations that span blockdevs are accounted for
+    /// exactly, using blockdevs that are backed by no device.
+    fn test_alloc_memory_backed() {
+        let pool_uuid = Uuid::new_v4();
+        let mut data_tier = DataTier::new(BlockDevMgr::memory_backed(
+            pool_uuid,
+            &[Sectors(IEC::Mi), Sectors(2 * IEC::Mi)],
+        ));
+        let avail_space = data_tier.block_mgr.avail_space();
+        let first_avail = data_tier.block_mgr.blockdevs()[0].1.available();
+
+        // Exhaust the first blockdev, and take a little from the second.
+        let request = first_avail + Sectors(100);
+        assert_eq!(
+            data_tier.alloc(request),
+            CapacityDelta {
+                before: Sectors(0),
+                after: request,
+            }
+        );
+        assert_eq!(data_tier.capacity(), request);
+        assert_eq!(data_tier.block_mgr.avail_space(), avail_space - request);
+        assert_eq!(data_tier.segments.len(), 2);
+
+        // A further allocation from the second blockdev is contiguous with
+        // the last, and so is coalesced with it.
+        assert_eq!(data_tier.alloc(Sectors(100)).grown(), Sectors(100));
+        assert_eq!(data_tier.segments.len(), 2);
+        assert_eq!(data_tier.segments[1].segment.length, Sectors(200));
+
+        let delta = data_tier.alloc(avail_space);
+        assert_eq!(delta.before, delta.after);
+        assert_eq!(delta.grown(), Sectors(0));
+        assert_eq!(
+            data_tier.block_mgr.avail_space(),
+            avail_space - request - Sectors(100)
+        );
+    }
+
+    #[test]
+    /// Verify that requests are rounded up to whole allocation chunks, even
+    /// if only the unrounded request could be satisfied.
+    fn test_alloc_chunk() {
+        let pool_uuid = Uuid::new_v4();
+        let mut data_tier = DataTier::new(BlockDevMgr::memory_backed(
+            pool_uuid,
+            &[Sectors(IEC::Mi)],
+        ));
+        assert!(data_tier.block_mgr.set_alloc_chunk(Sectors(0)).is_err());
+
+        // Leave exactly 100 sectors available
+        let request = data_tier.block_mgr.avail_space() - Sectors(100);
+        assert_eq!(data_tier.alloc(request).grown(), request);
+
+        data_tier.block_mgr.set_alloc_chunk(Sectors(64)).unwrap();
+        assert_eq!(data_tier.alloc(Sectors(10)).grown(), Sectors(64));
+        assert_eq!(data_tier.alloc(Sectors(30)).grown(), Sectors(0));
+        assert_eq!(data_tier.capacity(), request + Sectors(64));
+        assert_eq!(data_tier.block_mgr.avail_space(), Sectors(36));
+        // No chunk-aligned value is as large as the largest Sectors.
+        assert_eq!(data_tier.block_mgr.round_to_chunk(Sectors(!0)), None);
+    }
+
+    #[test]
+    /// Verify that a data tier can not be set up from blockdevs that claim
+    /// the same Stratis UUID, and that the error names both devnodes.
+    fn test_setup_duplicate_uuids() {
+        use std::io::Cursor;
+        use std::path::PathBuf;
+
+        let pool_uuid = Uuid::new_v4();
+        let dev_uuid = Uuid::new_v4();
+        let blockdev = |index, devnode: &str| {
+            StratBlockDev::initialize_for_test(
+                &mut Cursor::new(vec![]),
+                pool_uuid,
+                dev_uuid,
+                index,
+                PathBuf::from(devnode),
+                Sectors(IEC::Mi),
+                &[],
+            )
+        };
+
+        let block_mgr = BlockDevMgr::new(
+            vec![blockdev(0, "/dev/sda"), blockdev(1, "/dev/sdb")],
+            None,
+        );
+        match DataTier::setup(block_mgr, &[]) {
+            Err(StratisError::Engine(ErrorEnum::Invalid, msg)) => {
+                assert!(msg.contains(&dev_uuid.to_string()));
+                assert!(msg.contains("/dev/sda") && msg.contains("/dev/sdb"));
+            }
+            res => panic!("expected a duplicate UUID error, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    /// Verify that a data tier can only be set up from segments that lie
+    /// past the metadata and within the size of their blockdev, and that do
+    /// not overlap, and that adjacent segments are merged on setup.
+    fn test_setup_segment_validation() {
+        let size = Sectors(IEC::Mi);
+        let setup = |segments: &[(Sectors, Sectors)]| {
+            let block_mgr = BlockDevMgr::memory_backed(Uuid::new_v4(), &[size]);
+            let (uuid, metadata_size) = {
+                let (uuid, bd) = block_mgr.blockdevs()[0];
+                (uuid, bd.metadata_size())
+            };
+            let triples: Vec<(DevUuid, Sectors, Sectors)> = segments
+                .iter()
+                .map(|&(start, length)| (uuid, metadata_size + start, length))
+                .collect();
+            DataTier::setup(block_mgr, &triples).map(|data_tier| data_tier.segments.len())
+        };
+        let check_invalid = |res: StratisResult<usize>| match res {
+            Err(StratisError::Engine(ErrorEnum::Invalid, _)) => {}
+            res => panic!("expected an invalid segment error, got {:?}", res),
+        };
+
+        assert_eq!(
+            setup(&[(Sectors(0), Sectors(10)), (Sectors(10), Sectors(10))]).unwrap(),
+            1
+        );
+        assert_eq!(
+            setup(&[(Sectors(10), Sectors(10)), (Sectors(0), Sectors(10))]).unwrap(),
+            2
+        );
+        check_invalid(setup(&[(Sectors(0), size)]));
+        check_invalid(setup(&[(Sectors(0), Sectors(10)), (Sectors(5), Sectors(10))]));
+        check_invalid(setup(&[(Sectors(10), Sectors(10)), (Sectors(0), Sectors(11))]));
+
+        let block_mgr = BlockDevMgr::memory_backed(Uuid::new_v4(), &[size]);
+        let uuid = block_mgr.blockdevs()[0].0;
+        check_invalid(
+            DataTier::setup(block_mgr, &[(uuid, Sectors(0), Sectors(10))])
+                .map(|data_tier| data_tier.segments.len()),
+        );
+    }
+
+    #[test]
+    /// Verify that a data tier set up from the segments it would record is
+    /// mapped exactly as the original.
+    fn test_record_segments() {
+        let mut data_tier = DataTier::new(BlockDevMgr::memory_backed(
+            Uuid::new_v4(),
+            &[Sectors(IEC::Mi), Sectors(IEC::Mi)],
+        ));
+        let first_avail = data_tier.block_mgr.blockdevs()[0].1.available();
+        data_tier.alloc(first_avail + Sectors(100));
+        data_tier.alloc(Sectors(50));
+        data_tier.alloc(Sectors(10));
+        assert_eq!(data_tier.segments.len(), 2);
+
+        let triples = data_tier.record_segments();
+        assert_eq!(triples, data_tier.segments.record());
+        let table = map_to_dm(&data_tier.segments);
+        let capacity = data_tier.capacity();
+
+        let setup = DataTier::setup(data_tier.block_mgr, &triples).unwrap();
+        assert_eq!(setup.record_segments(), triples);
+        assert_eq!(map_to_dm(&setup.segments), table);
+        assert_eq!(setup.capacity(), capacity);
+    }
+
     /// Put the data tier through some paces. Make it, alloc a small amount,
     /// add some more blockdevs, allocate enough that the newly added blockdevs
     /// must be allocated from for success.
@@ -177,7 +530,7 @@ mod tests {
 
         let pool_uuid = Uuid::new_v4();
 
-        let mgr = BlockDevMgr::initialize(pool_uuid, paths1, MIN_MDA_SECTORS, false).unwrap();
+        let mgr = BlockDevMgr::initialize(pool_uuid, paths1, MIN_MDA_SECTORS, None).unwrap();
 
         let mut data_tier = DataTier::new(mgr);
 
@@ -193,14 +546,18 @@ mod tests {
         let request_amount = data_tier.block_mgr.avail_space() / 2usize;
         assert!(request_amount != Sectors(0));
 
-        assert!(data_tier.alloc(request_amount));
+        assert_eq!(data_tier.capacity(), Sectors(0));
+
+        assert!(data_tier.alloc(request_amount).grown() >= request_amount);
 
         // A data tier w/ some amount allocated
         assert!(data_tier.capacity() >= request_amount);
         assert_eq!(data_tier.current_capacity(), current_capacity);
         capacity = data_tier.capacity();
 
-        data_tier.add(pool_uuid, paths2, false).unwrap();
+        let (_, delta) = data_tier.add(pool_uuid, paths2, None).unwrap();
+        assert_eq!(delta.before, current_capacity);
+        assert_eq!(delta.after, data_tier.current_capacity());
 
         // A data tier w/ additional blockdevs added
         assert!(data_tier.current_capacity() > current_capacity);
@@ -209,7 +566,7 @@ mod tests {
         current_capacity = data_tier.current_capacity();
 
         // Allocate enough to get into the newly added block devices
-        assert!(data_tier.alloc(last_request_amount));
+        assert!(data_tier.alloc(last_request_amount).grown() >= last_request_amount);
 
         assert!(data_tier.capacity() >= request_amount + last_request_amount);
         assert_eq!(data_tier.current_capacity(), current_capacity);
@@ -52,6 +52,10 @@ pub enum DevOwnership {
     Ours(PoolUuid, DevUuid),
     Unowned,
     Theirs(String), // String is something useful to give back to end user about what's on device
+    /// The device has a valid Stratis BDA, but also a foreign signature,
+    /// as when a disk that was once a Stratis device is reused without
+    /// being wiped. Which of the two is current can not be known.
+    Ambiguous(PoolUuid, DevUuid, String),
 }
 
 /// Returns true if a device has no signature and is not one of the paths of a multipath device,
@@ -107,7 +111,23 @@ pub fn identify(devnode: &Path) -> StratisResult<DevOwnership> {
                 )))
             }
         } else {
-            Ok(DevOwnership::Theirs(signature(&device)))
+            // A foreign signature may have been written over part of a
+            // Stratis device, leaving its BDA intact. A device that can not
+            // be opened, or a BDA that can not be read, is not evidence of a
+            // Stratis device.
+            let identifiers = OpenOptions::new()
+                .read(true)
+                .open(devnode)
+                .map_err(StratisError::from)
+                .and_then(|mut f| StaticHeader::device_identifiers(&mut f));
+            match identifiers {
+                Ok(Some((pool_uuid, device_uuid))) => Ok(DevOwnership::Ambiguous(
+                    pool_uuid,
+                    device_uuid,
+                    signature(&device),
+                )),
+                _ => Ok(DevOwnership::Theirs(signature(&device))),
+            }
         }
     } else {
         Err(StratisError::Engine(
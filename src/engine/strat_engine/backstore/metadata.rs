@@ -19,7 +19,7 @@ use super::super::super::types::{DevUuid, PoolUuid};
 
 use super::super::device::SyncAll;
 
-pub use self::mda::{validate_mda_size, MIN_MDA_SECTORS};
+pub use self::mda::{max_metadata_len, validate_mda_size, MIN_MDA_SECTORS};
 
 const _BDA_STATIC_HDR_SIZE: usize = 16 * SECTOR_SIZE;
 const BDA_STATIC_HDR_SIZE: Bytes = Bytes(_BDA_STATIC_HDR_SIZE as u64);
@@ -200,6 +200,20 @@ impl BDA {
         self.regions.last_update_time()
     }
 
+    /// The index of the MDA region to which metadata was most recently
+    /// written, if any.
+    #[cfg(test)]
+    pub fn last_written_region(&self) -> Option<usize> {
+        self.regions.last_written_region()
+    }
+
+    /// The offset in bytes from the start of the device of the MDA region
+    /// with the given index, or None if there is no such region.
+    #[cfg(test)]
+    pub fn mda_region_offset(&self, index: usize) -> Option<u64> {
+        self.regions.region_offset(BDA_STATIC_HDR_SIZE, index)
+    }
+
     /// The UUID of the device.
     pub fn dev_uuid(&self) -> DevUuid {
         self.header.dev_uuid
@@ -225,12 +239,23 @@ impl BDA {
         self.regions.max_data_size()
     }
 
+    /// The number of sectors occupied by the MDA regions.
+    pub fn mda_size(&self) -> Sectors {
+        self.header.mda_size
+    }
+
     /// Timestamp when the device was initialized.
     pub fn initialization_time(&self) -> u64 {
         self.header.initialization_time
     }
 }
 
+/// The number of sectors that a BDA with MDA regions occupying mda_size
+/// sectors altogether would occupy, if initialized now.
+pub fn bda_size(mda_size: Sectors) -> Sectors {
+    BDA_STATIC_HDR_SIZE.sectors() + mda_size + MDA_RESERVED_SECTORS
+}
+
 #[derive(Eq, PartialEq)]
 pub struct StaticHeader {
     blkdev_size: Sectors,
@@ -329,7 +354,7 @@ impl StaticHeader {
             }
             (Err(_), Err(_)) => {
                 let err_str = "Appeared to be a Stratis device, but no valid sigblock found";
-                Err(StratisError::Engine(ErrorEnum::Invalid, err_str.into()))
+                Err(StratisError::Engine(ErrorEnum::Corrupt, err_str.into()))
             }
         }
     }
@@ -378,7 +403,7 @@ impl StaticHeader {
         let crc = crc32::checksum_castagnoli(&buf[4..SECTOR_SIZE]);
         if crc != LittleEndian::read_u32(&buf[..4]) {
             return Err(StratisError::Engine(
-                ErrorEnum::Invalid,
+                ErrorEnum::Corrupt,
                 "header CRC invalid".into(),
             ));
         }
@@ -494,8 +519,12 @@ mod mda {
         /// Construct MDARegions from data on the disk.
         /// Note that this method is always called in a context where a
         /// StaticHeader has already been read. Therefore, it
-        /// constitutes an error if it is not possible to discover two
-        /// well-formed MDAHeaders for this device.
+        /// constitutes an error if it is not possible to discover a
+        /// well-formed MDAHeader for this device. A region is only valid if
+        /// the metadata it describes can be read back; if one region is
+        /// not valid, as when a write to it was interrupted, it is treated
+        /// as empty, so that the newest valid region is the one loaded from,
+        /// and the invalid one is the next to be written.
         pub fn load<F>(header_size: Bytes, size: Sectors, f: &mut F) -> StratisResult<MDARegions>
         where
             F: Read + Seek,
@@ -515,7 +544,12 @@ mod mda {
                     per_region_size,
                 )))?;
                 f.read_exact(&mut hdr_buf)?;
-                Ok(MDAHeader::from_buf(&hdr_buf, per_region_size)?)
+                let header = MDAHeader::from_buf(&hdr_buf, per_region_size)?;
+                if let Some(ref header) = header {
+                    // The file is positioned just after the header.
+                    header.load_region(f)?;
+                }
+                Ok(header)
             };
 
             // Get an MDAHeader for the given index.
@@ -525,10 +559,20 @@ mod mda {
                 load_a_region(index).or_else(|_| load_a_region(index + 2))
             };
 
-            Ok(MDARegions {
-                region_size,
-                mdas: [get_mda(0)?, get_mda(1)?],
-            })
+            let mdas = match (get_mda(0), get_mda(1)) {
+                (Ok(mda0), Ok(mda1)) => [mda0, mda1],
+                (Ok(Some(mda0)), Err(err)) => {
+                    warn!("MDA region 1 is invalid, using only region 0: {}", err);
+                    [Some(mda0), None]
+                }
+                (Err(err), Ok(Some(mda1))) => {
+                    warn!("MDA region 0 is invalid, using only region 1: {}", err);
+                    [None, Some(mda1)]
+                }
+                (Err(err), _) | (_, Err(err)) => return Err(err),
+            };
+
+            Ok(MDARegions { region_size, mdas })
         }
 
         /// Write metadata to the older of the metadata regions.
@@ -537,7 +581,9 @@ mod mda {
         /// If time specified is earlier than the last update time, return an
         /// error. If the size of the data is greater than the available space,
         /// return an error. If there is an error when writing the data, return
-        /// an error.
+        /// an error. Each region is synced to the device once written, so
+        /// that the data is durable when this method returns; a failure to
+        /// sync is an error like any other failure to write.
         pub fn save_state<F>(
             &mut self,
             header_size: Bytes,
@@ -649,6 +695,26 @@ mod mda {
         pub fn last_update_time(&self) -> Option<&DateTime<Utc>> {
             self.mdas[self.newer()].as_ref().map(|h| &h.last_updated)
         }
+
+        /// The index of the region most recently written, and so the one
+        /// metadata is loaded from, or None if no metadata has been written.
+        /// Successive writes alternate between the regions.
+        #[cfg(test)]
+        pub fn last_written_region(&self) -> Option<usize> {
+            let newer = self.newer();
+            self.mdas[newer].as_ref().map(|_| newer)
+        }
+
+        /// The offset from start of device of the region with the given
+        /// index, counting the copies, or None if there is no such region.
+        #[cfg(test)]
+        pub fn region_offset(&self, header_size: Bytes, index: usize) -> Option<u64> {
+            if index < NUM_MDA_REGIONS {
+                Some(MDARegions::mda_offset(header_size, index, self.region_size.bytes()))
+            } else {
+                None
+            }
+        }
     }
 
     #[derive(Debug)]
@@ -685,7 +751,7 @@ mod mda {
         ) -> StratisResult<Option<MDAHeader>> {
             if LittleEndian::read_u32(&buf[..4]) != crc32::checksum_castagnoli(&buf[4..]) {
                 return Err(StratisError::Engine(
-                    ErrorEnum::Invalid,
+                    ErrorEnum::Corrupt,
                     "MDA region header CRC".into(),
                 ));
             }
@@ -750,7 +816,7 @@ mod mda {
 
             if self.data_crc != crc32::checksum_castagnoli(&data_buf) {
                 return Err(StratisError::Engine(
-                    ErrorEnum::Invalid,
+                    ErrorEnum::Corrupt,
                     "MDA region data CRC".into(),
                 ));
             }
@@ -768,11 +834,22 @@ mod mda {
                 // available region > header size
                 available - MDA_REGION_HDR_SIZE
             );
-            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            return Err(StratisError::Engine(ErrorEnum::NotEnoughSpace, err_msg));
         };
         Ok(())
     }
 
+    /// The length of the longest metadata that MDA regions occupying size
+    /// sectors altogether can hold.
+    pub fn max_metadata_len(size: Sectors) -> Bytes {
+        let region_size = (size / NUM_MDA_REGIONS).bytes();
+        if region_size > MDA_REGION_HDR_SIZE {
+            region_size - MDA_REGION_HDR_SIZE
+        } else {
+            Bytes(0)
+        }
+    }
+
     /// Validate MDA size
     pub fn validate_mda_size(size: Sectors) -> StratisResult<()> {
         if size % NUM_MDA_REGIONS != Sectors(0) {
@@ -802,12 +879,23 @@ mod mda {
     mod tests {
         use std::io::Cursor;
 
-        use chrono::Utc;
+        use chrono::{Duration, Utc};
         use quickcheck::{QuickCheck, TestResult};
 
         use super::super::*;
         use super::*;
 
+        #[test]
+        /// Verify that the longest metadata that fits is one region less the
+        /// region header, and that a too small MDA holds nothing.
+        fn test_max_metadata_len() {
+            assert_eq!(
+                max_metadata_len(MIN_MDA_SECTORS),
+                (MIN_MDA_SECTORS / NUM_MDA_REGIONS).bytes() - MDA_REGION_HDR_SIZE
+            );
+            assert_eq!(max_metadata_len(Sectors(0)), Bytes(0));
+        }
+
         #[test]
         /// Verify that default MDAHeader is all 0s except for CRC.
         fn test_default_mda_header() {
@@ -828,6 +916,59 @@ mod mda {
             assert!(regions.last_update_time().is_none());
         }
 
+        #[test]
+        /// Verify that successive writes alternate between the regions, that
+        /// if the data in the most recently written region is corrupted the
+        /// older region is loaded from, and that the corrupted region is the
+        /// next to be written.
+        fn test_mda_region_rotation() {
+            let buf_length = *(BDA_STATIC_HDR_SIZE + 4usize * MIN_MDA_SECTORS.bytes()) as usize;
+            let mut buf = Cursor::new(vec![0; buf_length]);
+            let mut regions =
+                MDARegions::initialize(BDA_STATIC_HDR_SIZE, MIN_MDA_SECTORS, &mut buf).unwrap();
+            assert_eq!(regions.last_written_region(), None);
+
+            let time = Utc::now();
+            regions
+                .save_state(BDA_STATIC_HDR_SIZE, &time, b"first", &mut buf)
+                .unwrap();
+            assert_eq!(regions.last_written_region(), Some(0));
+            let later = time + Duration::seconds(1);
+            regions
+                .save_state(BDA_STATIC_HDR_SIZE, &later, b"second", &mut buf)
+                .unwrap();
+            assert_eq!(regions.last_written_region(), Some(1));
+
+            // Corrupt the data of region 1 and of its copy, region 3.
+            let region_size = (MIN_MDA_SECTORS / NUM_MDA_REGIONS).bytes();
+            for index in &[1, 3] {
+                let offset = MDARegions::mda_offset(BDA_STATIC_HDR_SIZE, *index, region_size)
+                    + _MDA_REGION_HDR_SIZE as u64;
+                buf.get_mut()[offset as usize] ^= 0xff;
+            }
+
+            let mut regions =
+                MDARegions::load(BDA_STATIC_HDR_SIZE, MIN_MDA_SECTORS, &mut buf).unwrap();
+            assert_eq!(regions.last_written_region(), Some(0));
+            assert_eq!(regions.last_update_time(), Some(&time));
+            assert_eq!(
+                regions.load_state(BDA_STATIC_HDR_SIZE, &mut buf).unwrap(),
+                Some(b"first".to_vec())
+            );
+
+            let latest = later + Duration::seconds(1);
+            regions
+                .save_state(BDA_STATIC_HDR_SIZE, &latest, b"third", &mut buf)
+                .unwrap();
+            assert_eq!(regions.last_written_region(), Some(1));
+            let regions =
+                MDARegions::load(BDA_STATIC_HDR_SIZE, MIN_MDA_SECTORS, &mut buf).unwrap();
+            assert_eq!(
+                regions.load_state(BDA_STATIC_HDR_SIZE, &mut buf).unwrap(),
+                Some(b"third".to_vec())
+            );
+        }
+
         #[test]
         /// Using an arbitrary data buffer, construct an mda header buffer
         /// Read the mda header buffer twice.
@@ -985,6 +1126,21 @@ mod tests {
             .quickcheck(test_ownership as fn(u64, u32) -> TestResult);
     }
 
+    #[test]
+    /// Verify that bda_size() predicts the size of an initialized BDA.
+    fn test_bda_size() {
+        let mda_size = MIN_MDA_SECTORS * 2usize;
+        let bda = BDA::initialize(
+            &mut Cursor::new(vec![]),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            mda_size,
+            Sectors(IEC::Gi),
+            Utc::now().timestamp() as u64,
+        ).unwrap();
+        assert_eq!(bda_size(mda_size), bda.size());
+    }
+
     #[test]
     /// Construct an arbitrary StaticHeader object.
     /// Initialize a BDA.
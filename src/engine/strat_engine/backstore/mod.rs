@@ -8,6 +8,7 @@ mod blockdev;
 mod blockdevmgr;
 mod cache_tier;
 mod cleanup;
+mod crypt;
 mod data_tier;
 pub mod device;
 mod metadata;
@@ -17,6 +18,7 @@ mod util;
 
 pub use self::backstore::Backstore;
 pub use self::blockdev::StratBlockDev;
+pub use self::blockdevmgr::ForceToken;
 pub use self::device::blkdev_size;
 pub use self::device::is_stratis_device;
 pub use self::metadata::MIN_MDA_SECTORS;
@@ -127,7 +127,42 @@ impl RangeAllocator {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Mark ranges previously marked as used as now unused.
+    /// Return an error, and leave the allocator unchanged, if any range is
+    /// not entirely in use or if any two ranges overlap.
+    pub fn release(&mut self, ranges: &[(Sectors, Sectors)]) -> StratisResult<()> {
+        let mut sorted = ranges
+            .iter()
+            .filter(|&&(_, len)| len != Sectors(0))
+            .cloned()
+            .collect::<Vec<_>>();
+        sorted.sort_unstable();
+
+        for &(off, len) in &sorted {
+            self.check_for_overflow(off, len)?;
+            match self.used.range(..=off).next_back() {
+                Some((&used_off, &used_len)) if used_off + used_len >= off + len => {}
+                _ => {
+                    let err_msg = format!("range ({}, {}) is not entirely in use", off, len);
+                    return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+            }
+        }
+
+        for pair in sorted.windows(2) {
+            if pair[0].0 + pair[0].1 > pair[1].0 {
+                let err_msg = format!(
+                    "range ({}, {}) overlaps range ({}, {})",
+                    pair[0].0, pair[0].1, pair[1].0, pair[1].1
+                );
+                return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+
+        self.remove_ranges(&sorted);
+        Ok(())
+    }
+
     /// Mark ranges previously marked as used as now unused.
     fn remove_ranges(&mut self, to_free: &[(Sectors, Sectors)]) -> () {
         for &(off, len) in to_free {
@@ -190,17 +225,45 @@ impl RangeAllocator {
         self.used.values().cloned().sum()
     }
 
-    /// Get a list of (offset, length) segments that are in use
-    fn used_ranges(&self) -> Vec<(Sectors, Sectors)> {
+    /// Verify that the internal state of the allocator is consistent.
+    /// The used ranges must be non-empty, must lie within the limit, and
+    /// must neither overlap nor abut one another, since abutting ranges
+    /// are always merged on insertion.
+    pub fn check_invariants(&self) -> StratisResult<()> {
+        let mut prev_end = None;
+        for (&off, &len) in &self.used {
+            if len == Sectors(0) {
+                let err_msg = format!("empty range recorded at offset {}", off);
+                return Err(StratisError::Engine(ErrorEnum::Error, err_msg));
+            }
+            if let Some(prev_end) = prev_end {
+                if off <= prev_end {
+                    let err_msg = format!(
+                        "range ({}, {}) overlaps or abuts previous range ending at {}",
+                        off, len, prev_end
+                    );
+                    return Err(StratisError::Engine(ErrorEnum::Error, err_msg));
+                }
+            }
+            self.check_for_overflow(off, len)?;
+            prev_end = Some(off + len);
+        }
+        Ok(())
+    }
+
+    /// Get a list of (offset, length) segments that are in use, in order
+    /// of offset
+    pub fn used_extents(&self) -> Vec<(Sectors, Sectors)> {
         self.used.iter().map(|(k, v)| (*k, *v)).collect()
     }
 
-    /// Get a list of (offset, length) segments that are not in use
-    fn avail_ranges(&self) -> Vec<(Sectors, Sectors)> {
+    /// Get a list of (offset, length) segments that are not in use, in
+    /// order of offset
+    pub fn free_extents(&self) -> Vec<(Sectors, Sectors)> {
         let mut free = Vec::new();
 
         // Insert an entry to mark the end so the fold works correctly
-        let mut used = self.used_ranges();
+        let mut used = self.used_extents();
         used.push((self.limit, Sectors(0)));
 
         used.into_iter().fold(Sectors(0), |prev_end, (start, len)| {
@@ -213,6 +276,15 @@ impl RangeAllocator {
         free
     }
 
+    /// The length of the largest range of contiguous available sectors.
+    pub fn largest_available(&self) -> Sectors {
+        self.free_extents()
+            .into_iter()
+            .map(|(_, len)| len)
+            .max()
+            .unwrap_or(Sectors(0))
+    }
+
     /// Attempt to allocate. Returns number of sectors allocated (may
     /// be less than request, including zero) and a Vec<(offset,
     /// length)> of sectors successfully allocated.
@@ -222,7 +294,7 @@ impl RangeAllocator {
         let mut segs = Vec::new();
         let mut needed = amount;
 
-        for (start, len) in self.avail_ranges() {
+        for (start, len) in self.free_extents() {
             if needed == Sectors(0) {
                 break;
             }
@@ -239,12 +311,122 @@ impl RangeAllocator {
 
         (amount - needed, segs)
     }
+
 }
 
 #[cfg(test)]
 mod tests {
+    use quickcheck::{QuickCheck, TestResult};
+
     use super::*;
 
+    /// The (offset, length) runs of consecutive sectors for which in_use
+    /// returns the given value, in order of offset.
+    fn runs(map: &[bool], in_use: bool) -> Vec<(Sectors, Sectors)> {
+        let mut runs = Vec::new();
+        let mut start = None;
+        for (i, &used) in map.iter().chain(&[!in_use]).enumerate() {
+            match (start, used == in_use) {
+                (None, true) => start = Some(i),
+                (Some(s), false) => {
+                    runs.push((Sectors(s as u64), Sectors((i - s) as u64)));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        runs
+    }
+
+    #[test]
+    /// Apply an arbitrary sequence of requests and releases to an
+    /// allocator, and to a map of the sectors in use maintained alongside
+    /// it. After each operation, verify that the allocator is consistent,
+    /// that it agrees with the map, and that it never allocates a sector
+    /// twice. Releases are of ranges previously allocated, or of arbitrary
+    /// ranges, which must be refused unless entirely in use.
+    fn prop_allocator_operations() {
+        fn allocator_operations(limit: u16, ops: Vec<(u8, u16, u16)>) -> TestResult {
+            let limit = usize::from(limit % 512) + 1;
+            let mut allocator = RangeAllocator::new(Sectors(limit as u64), &[]).unwrap();
+            let mut map = vec![false; limit];
+            let mut allocated: Vec<(Sectors, Sectors)> = Vec::new();
+
+            let mark = |map: &mut Vec<bool>, (off, len): (Sectors, Sectors), used: bool| {
+                for i in *off..*off + *len {
+                    if map[i as usize] == used {
+                        return false;
+                    }
+                    map[i as usize] = used;
+                }
+                true
+            };
+
+            for (kind, amount, offset) in ops {
+                let amount = Sectors(u64::from(amount) % (limit as u64 + 2));
+                match kind % 3 {
+                    0 => {
+                        let available = allocator.available();
+                        let (gotten, segs) = allocator.request(amount);
+                        if gotten != min(amount, available)
+                            || segs.iter().map(|&(_, len)| len).sum::<Sectors>() != gotten
+                        {
+                            return TestResult::failed();
+                        }
+                        for seg in segs {
+                            if !mark(&mut map, seg, true) {
+                                return TestResult::failed();
+                            }
+                            allocated.push(seg);
+                        }
+                    }
+                    1 => {
+                        if allocated.is_empty() {
+                            continue;
+                        }
+                        let seg = allocated.swap_remove(usize::from(offset) % allocated.len());
+                        if allocator.release(&[seg]).is_err() || !mark(&mut map, seg, false) {
+                            return TestResult::failed();
+                        }
+                    }
+                    _ => {
+                        let off = Sectors(u64::from(offset) % limit as u64);
+                        let len = min(amount, Sectors(limit as u64) - off);
+                        let in_use = (*off..*off + *len).all(|i| map[i as usize]);
+                        let released = allocator.release(&[(off, len)]).is_ok();
+                        if released != in_use {
+                            return TestResult::failed();
+                        }
+                        if released {
+                            mark(&mut map, (off, len), false);
+                            // Forget the allocations that were released,
+                            // wholly or in part, so that none is released
+                            // twice.
+                            allocated.retain(|&(a_off, a_len)| {
+                                (*a_off..*a_off + *a_len).all(|i| map[i as usize])
+                            });
+                        }
+                    }
+                }
+
+                let used = map.iter().filter(|&&used| used).count() as u64;
+                if allocator.check_invariants().is_err()
+                    || allocator.used() != Sectors(used)
+                    || allocator.used() + allocator.available() != allocator.capacity()
+                    || allocator.used_extents() != runs(&map, true)
+                    || allocator.free_extents() != runs(&map, false)
+                {
+                    return TestResult::failed();
+                }
+            }
+            TestResult::passed()
+        }
+
+        QuickCheck::new()
+            .tests(200)
+            .quickcheck(allocator_operations as fn(u16, Vec<(u8, u16, u16)>) -> TestResult);
+    }
+
     #[test]
     /// Test proper operation of RangeAllocator.
     /// 1. Instantiate a RangeAllocator.
@@ -294,7 +476,7 @@ mod tests {
             (Sectors(30), Sectors(10)),
         ];
         let allocator = RangeAllocator::new(Sectors(128), &ranges).unwrap();
-        let used = allocator.used_ranges();
+        let used = allocator.used_extents();
         assert_eq!(used.len(), 1);
         assert_eq!(used[0], (Sectors(10), Sectors(30)));
     }
@@ -314,7 +496,7 @@ mod tests {
             .insert_ranges(&[(Sectors(30), Sectors(10))])
             .unwrap();
 
-        let used = allocator.used_ranges();
+        let used = allocator.used_extents();
         assert_eq!(used.len(), 1);
         assert_eq!(used[0], (Sectors(10), Sectors(30)));
     }
@@ -333,27 +515,134 @@ mod tests {
             .unwrap();
 
         allocator.remove_ranges(&[(Sectors(20), Sectors(3))]);
-        let used = allocator.used_ranges();
+        let used = allocator.used_extents();
         assert_eq!(used.len(), 1);
         assert_eq!(used[0], (Sectors(23), Sectors(17)));
 
         allocator.remove_ranges(&[(Sectors(36), Sectors(4))]);
-        let used = allocator.used_ranges();
+        let used = allocator.used_extents();
         assert_eq!(used.len(), 1);
         assert_eq!(used[0], (Sectors(23), Sectors(13)));
 
         allocator.remove_ranges(&[(Sectors(24), Sectors(2))]);
-        let used = allocator.used_ranges();
+        let used = allocator.used_extents();
         assert_eq!(used.len(), 2);
         assert_eq!(used[0], (Sectors(23), Sectors(1)));
         assert_eq!(used[1], (Sectors(26), Sectors(10)));
 
         allocator.remove_ranges(&[(Sectors(26), Sectors(10))]);
-        let used = allocator.used_ranges();
+        let used = allocator.used_extents();
         assert_eq!(used.len(), 1);
         assert_eq!(used[0], (Sectors(23), Sectors(1)));
     }
 
+    #[test]
+    /// Verify that check_invariants() accepts the states produced by
+    /// normal operation and rejects overlapping or abutting ranges.
+    fn test_allocator_check_invariants() {
+        let mut allocator = RangeAllocator::new(Sectors(128), &[]).unwrap();
+        allocator.check_invariants().unwrap();
+
+        allocator
+            .insert_ranges(&[(Sectors(10), Sectors(10)), (Sectors(40), Sectors(10))])
+            .unwrap();
+        allocator.check_invariants().unwrap();
+
+        allocator.request(Sectors(64));
+        allocator.check_invariants().unwrap();
+
+        allocator.remove_ranges(&[(Sectors(20), Sectors(5))]);
+        allocator.check_invariants().unwrap();
+
+        allocator.used.insert(Sectors(20), Sectors(1));
+        assert!(allocator.check_invariants().is_err());
+
+        let mut allocator = RangeAllocator::new(Sectors(128), &[]).unwrap();
+        allocator.used.insert(Sectors(0), Sectors(10));
+        allocator.used.insert(Sectors(10), Sectors(10));
+        assert!(allocator.check_invariants().is_err());
+
+        let mut allocator = RangeAllocator::new(Sectors(128), &[]).unwrap();
+        allocator.used.insert(Sectors(120), Sectors(10));
+        assert!(allocator.check_invariants().is_err());
+    }
+
+    #[test]
+    /// Verify that release() frees exactly the ranges specified, and that
+    /// it fails without changing the allocator when a range is not in use
+    /// or when the ranges overlap.
+    fn test_allocator_release() {
+        let mut allocator = RangeAllocator::new(Sectors(128), &[]).unwrap();
+        allocator.request(Sectors(100));
+
+        allocator
+            .release(&[(Sectors(50), Sectors(10)), (Sectors(10), Sectors(10))])
+            .unwrap();
+        assert_eq!(allocator.available(), Sectors(48));
+        assert_eq!(
+            allocator.used_extents(),
+            vec![
+                (Sectors(0), Sectors(10)),
+                (Sectors(20), Sectors(30)),
+                (Sectors(60), Sectors(40)),
+            ]
+        );
+
+        assert!(allocator.release(&[(Sectors(15), Sectors(10))]).is_err());
+        assert!(allocator.release(&[(Sectors(95), Sectors(10))]).is_err());
+        assert!(
+            allocator
+                .release(&[(Sectors(20), Sectors(10)), (Sectors(25), Sectors(10))])
+                .is_err()
+        );
+        assert_eq!(allocator.available(), Sectors(48));
+    }
+
+    #[test]
+    /// Verify that largest_available() tracks the largest free range.
+    fn test_allocator_largest_available() {
+        let mut allocator = RangeAllocator::new(Sectors(128), &[]).unwrap();
+        assert_eq!(allocator.largest_available(), Sectors(128));
+
+        allocator
+            .insert_ranges(&[(Sectors(10), Sectors(10)), (Sectors(40), Sectors(80))])
+            .unwrap();
+        assert_eq!(allocator.largest_available(), Sectors(20));
+
+        allocator.request(Sectors(15));
+        assert_eq!(allocator.largest_available(), Sectors(15));
+
+        allocator.request(allocator.available());
+        assert_eq!(allocator.largest_available(), Sectors(0));
+    }
+
+    #[test]
+    /// Verify that the free and used extents partition the allocator's
+    /// range after a sequence of requests and releases.
+    fn test_allocator_extents() {
+        let mut allocator =
+            RangeAllocator::new(Sectors(128), &[(Sectors(0), Sectors(8))]).unwrap();
+        assert_eq!(allocator.used_extents(), vec![(Sectors(0), Sectors(8))]);
+        assert_eq!(allocator.free_extents(), vec![(Sectors(8), Sectors(120))]);
+
+        allocator.request(Sectors(40));
+        allocator
+            .release(&[(Sectors(16), Sectors(8)), (Sectors(40), Sectors(8))])
+            .unwrap();
+        assert_eq!(
+            allocator.used_extents(),
+            vec![(Sectors(0), Sectors(16)), (Sectors(24), Sectors(16))]
+        );
+        assert_eq!(
+            allocator.free_extents(),
+            vec![(Sectors(16), Sectors(8)), (Sectors(40), Sectors(88))]
+        );
+
+        allocator.request(allocator.available());
+        assert_eq!(allocator.used_extents(), vec![(Sectors(0), Sectors(128))]);
+        assert_eq!(allocator.free_extents(), vec![]);
+    }
+
     #[test]
     /// Verify that the largest possible limit may be used for the
     /// allocator.
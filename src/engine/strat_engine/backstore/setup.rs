@@ -17,12 +17,14 @@ use stratis::{ErrorEnum, StratisError, StratisResult};
 
 use super::super::super::types::{BlockDevTier, DevUuid, PoolUuid};
 
-use super::super::serde_structs::{BackstoreSave, BlockDevSave, PoolSave};
+use super::super::serde_structs::{
+    BackstoreSave, BlockDevSave, PoolSave, BLOCKDEV_SAVE_SCHEMA_VERSION,
+};
 
 use super::blockdev::StratBlockDev;
 use super::device::blkdev_size;
 use super::metadata::{StaticHeader, BDA};
-use super::util::get_stratis_block_devices;
+use super::util::{get_stratis_block_devices, hw_lookup, thin_backed_lookup};
 
 /// Find all Stratis devices.
 ///
@@ -109,6 +111,42 @@ pub fn get_metadata(
     Err(StratisError::Engine(ErrorEnum::NotFound, err_str.into()))
 }
 
+/// Verify that the device is at least as large as the size recorded in its
+/// BDA. If it has shrunk, the allocator could hand out sectors that do not
+/// exist, so this is an error naming the device.
+fn check_dev_size(
+    device: Device,
+    devnode: &Path,
+    bda: &BDA,
+    actual_size: Sectors,
+) -> StratisResult<()> {
+    let recorded_size = bda.dev_size();
+    if actual_size < recorded_size {
+        let err_msg = format!(
+            "Stratis device with device number {}, devnode {}, pool UUID {} and device UUID {} had recorded size ({}), but actual size is less at ({})",
+            device,
+            devnode.display(),
+            bda.pool_uuid(),
+            bda.dev_uuid(),
+            recorded_size,
+            actual_size
+        );
+        return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    Ok(())
+}
+
+/// Whether the hardware identifier of a device differs from the one
+/// recorded for it in the metadata, which suggests that the Stratis
+/// metadata has been copied onto a different disk. If either identifier is
+/// unknown, no difference can be detected.
+fn hardware_id_changed(recorded: &Option<String>, current: &Option<String>) -> bool {
+    match (recorded, current) {
+        (Some(recorded), Some(current)) => recorded != current,
+        _ => false,
+    }
+}
+
 /// Get all the blockdevs corresponding to this pool that can be obtained from
 /// the given devices. Sort the blockdevs in the order in which they were
 /// recorded in the metadata.
@@ -125,6 +163,27 @@ pub fn get_blockdevs(
     backstore_save: &BackstoreSave,
     devnodes: &HashMap<Device, PathBuf>,
 ) -> StratisResult<(Vec<StratBlockDev>, Vec<StratBlockDev>)> {
+    // Refuse to interpret blockdev metadata written according to a schema
+    // newer than any this version of stratisd knows about.
+    for bd_save in backstore_save.data_devs.iter().chain(
+        backstore_save
+            .cache_devs
+            .as_ref()
+            .map_or(&[] as &[BlockDevSave], |c| c.as_slice())
+            .iter(),
+    ) {
+        if bd_save.schema_version > BLOCKDEV_SAVE_SCHEMA_VERSION {
+            let err_msg = format!(
+                "Metadata for Stratis device with device UUID {} in pool with UUID {} has schema version {}, but the highest version supported is {}",
+                bd_save.uuid,
+                pool_uuid,
+                bd_save.schema_version,
+                BLOCKDEV_SAVE_SCHEMA_VERSION
+            );
+            return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+    }
+
     let recorded_data_map: HashMap<DevUuid, (usize, &BlockDevSave)> = backstore_save
         .data_devs
         .iter()
@@ -182,24 +241,8 @@ pub fn get_blockdevs(
         // Return an error if apparent size of Stratis block device appears to
         // have decreased since metadata was recorded or if size of block
         // device could not be obtained.
-        blkdev_size(&OpenOptions::new().read(true).open(devnode)?).and_then(|actual_size| {
-            let actual_size_sectors = actual_size.sectors();
-            let recorded_size = bda.dev_size();
-            if actual_size_sectors < recorded_size {
-                let err_msg = format!(
-                    "Stratis device with device number {}, devnode {}, pool UUID {} and device UUID {} had recorded size ({}), but actual size is less at ({})",
-                    device,
-                    devnode.display(),
-                    bda.pool_uuid(),
-                    bda.dev_uuid(),
-                    recorded_size,
-                    actual_size
-                );
-                Err(StratisError::Engine(ErrorEnum::Error, err_msg))
-            } else {
-                Ok(())
-            }
-        })?;
+        let actual_size = blkdev_size(&OpenOptions::new().read(true).open(devnode)?)?;
+        check_dev_size(device, devnode, &bda, actual_size.sectors())?;
 
         let dev_uuid = bda.dev_uuid();
 
@@ -229,17 +272,44 @@ pub fn get_blockdevs(
         // available to be allocated. If this fails, the most likely
         // conclusion is metadata corruption.
         let segments = segment_table.get(&dev_uuid);
-        Ok((
-            tier,
-            StratBlockDev::new(
-                device,
-                devnode.to_owned(),
-                bda,
-                segments.unwrap_or(&vec![]),
-                bd_save.user_info.clone(),
-                bd_save.hardware_info.clone(),
-            )?,
-        ))
+        let hw_id = hw_lookup(devnode).unwrap_or(None);
+        if hardware_id_changed(&bd_save.hardware_info, &hw_id) {
+            warn!(
+                "Stratis device with devnode {} and device UUID {} was recorded with hardware ID {:?}, but the device's hardware ID is {:?}",
+                devnode.display(),
+                dev_uuid,
+                bd_save.hardware_info,
+                hw_id
+            );
+        }
+        let mut blockdev = StratBlockDev::new(
+            device,
+            devnode.to_owned(),
+            bda,
+            segments.unwrap_or(&vec![]),
+            bd_save.user_info.clone(),
+            bd_save.hardware_info.clone(),
+        )?;
+        if let Some(ref key_desc) = bd_save.key_description {
+            blockdev.unlock(key_desc).map_err(|err| {
+                let err_msg = format!(
+                    "Stratis device with devnode {} and device UUID {} could not be unlocked with the key with description \"{}\", which may be missing from the kernel keyring: {}",
+                    devnode.display(),
+                    dev_uuid,
+                    key_desc,
+                    err
+                );
+                StratisError::Engine(ErrorEnum::Error, err_msg)
+            })?;
+        }
+        if thin_backed_lookup(device).unwrap_or(false) {
+            warn!(
+                "Stratis device with devnode {} and device UUID {} appears to be thinly provisioned or sparse; its backing store may run out before it is full",
+                devnode.display(),
+                dev_uuid
+            );
+        }
+        Ok((tier, blockdev))
     }
 
     let (mut datadevs, mut cachedevs): (Vec<StratBlockDev>, Vec<StratBlockDev>) = (vec![], vec![]);
@@ -276,17 +346,25 @@ pub fn get_blockdevs(
     ) -> StratisResult<Vec<StratBlockDev>> {
         let mut uuids = HashSet::new();
         let mut duplicate_uuids = Vec::new();
+        let mut devnodes: HashMap<DevUuid, &Path> = HashMap::new();
         for dev in &devs {
             let dev_uuid = dev.uuid();
             if !uuids.insert(dev_uuid) {
-                duplicate_uuids.push(dev_uuid);
+                duplicate_uuids.push(format!(
+                    "{} ({} and {})",
+                    dev_uuid,
+                    devnodes[&dev_uuid].display(),
+                    dev.devnode.display()
+                ));
+            } else {
+                devnodes.insert(dev_uuid, &dev.devnode);
             }
         }
 
         if !duplicate_uuids.is_empty() {
             let err_msg = format!(
-                "The following list of Stratis UUIDs were each claimed by more than one Stratis device: {:?}",
-                duplicate_uuids
+                "The following list of Stratis UUIDs were each claimed by more than one Stratis device: {}",
+                duplicate_uuids.join(", ")
             );
             return Err(StratisError::Engine(ErrorEnum::Invalid, err_msg.into()));
         }
@@ -314,3 +392,54 @@ pub fn get_blockdevs(
 
     Ok((datadevs, cachedevs))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use devicemapper::IEC;
+
+    use super::super::metadata::MIN_MDA_SECTORS;
+
+    use super::*;
+
+    #[test]
+    /// Verify that a device which is smaller than the size recorded in its
+    /// BDA is rejected, and that one that is the same size or larger is not.
+    fn test_check_dev_size() {
+        let size = Sectors(IEC::Mi);
+        let bda = BDA::initialize(
+            &mut Cursor::new(vec![]),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            MIN_MDA_SECTORS,
+            size,
+            Utc::now().timestamp() as u64,
+        ).unwrap();
+        let device = Device { major: 8, minor: 0 };
+        let devnode = Path::new("/dev/memory0");
+
+        assert!(check_dev_size(device, devnode, &bda, size).is_ok());
+        assert!(check_dev_size(device, devnode, &bda, size + Sectors(1)).is_ok());
+        match check_dev_size(device, devnode, &bda, size - Sectors(1)) {
+            Err(StratisError::Engine(ErrorEnum::Invalid, ref msg))
+                if msg.contains("/dev/memory0") => {}
+            res => panic!("expected a shrunk device error, got {:?}", res),
+        }
+    }
+
+    #[test]
+    /// Verify that a change of hardware identifier is detected only if both
+    /// the recorded and the current identifiers are known.
+    fn test_hardware_id_changed() {
+        let id = |id: &str| Some(id.to_owned());
+        assert!(hardware_id_changed(&id("0x5000c500a1b2c3d4"), &id("0x5000c500a1b2c3d5")));
+        assert!(!hardware_id_changed(&id("0x5000c500a1b2c3d4"), &id("0x5000c500a1b2c3d4")));
+        assert!(!hardware_id_changed(&id("0x5000c500a1b2c3d4"), &None));
+        assert!(!hardware_id_changed(&None, &id("0x5000c500a1b2c3d4")));
+        assert!(!hardware_id_changed(&None, &None));
+    }
+}
@@ -3,14 +3,63 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 // Utilities to support Stratis.
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
+use libmount;
 use libudev;
 
+use devicemapper::Device;
+
 use super::device::is_stratis_device;
-use stratis::StratisResult;
+use stratis::{ErrorEnum, StratisError, StratisResult};
+
+/// Whether a file of len bytes that occupies blocks 512 byte blocks on
+/// disk is sparse.
+fn is_sparse(len: u64, blocks: u64) -> bool {
+    blocks.saturating_mul(512) < len
+}
+
+/// Whether a SCSI disk's provisioning mode, as reported in sysfs, shows
+/// that the disk is thinly provisioned, i.e., that it unmaps blocks.
+fn is_thin_provisioning_mode(mode: &str) -> bool {
+    ["unmap", "writesame_16", "writesame_10"].contains(&mode.trim())
+}
+
+/// Find out whether a device appears to be backed by thinly provisioned or
+/// sparse storage, so that its size may overstate the space that can
+/// actually be written to it. This is a heuristic: a loop device is
+/// thin-backed if its backing file is sparse, and a SCSI disk, or a
+/// partition of one, if it reports a thin provisioning mode. Other devices
+/// are assumed not to be thin-backed.
+pub fn thin_backed_lookup(device: Device) -> StratisResult<bool> {
+    let sysfs_dir = PathBuf::from(format!("/sys/dev/block/{}", device));
+
+    let backing_file = sysfs_dir.join("loop").join("backing_file");
+    if backing_file.exists() {
+        let metadata = fs::metadata(fs::read_to_string(backing_file)?.trim())?;
+        return Ok(is_sparse(metadata.len(), metadata.blocks()));
+    }
+
+    let disk_dir = if sysfs_dir.join("partition").exists() {
+        sysfs_dir.join("..")
+    } else {
+        sysfs_dir
+    };
+    let scsi_disk_dir = disk_dir.join("device").join("scsi_disk");
+    if !scsi_disk_dir.exists() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(scsi_disk_dir)? {
+        let mode_file = entry?.path().join("provisioning_mode");
+        if mode_file.exists() && is_thin_provisioning_mode(&fs::read_to_string(mode_file)?) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
 
 /// Takes a libudev device entry and returns the properties as a HashMap.
 fn device_as_map(device: &libudev::Device) -> HashMap<String, String> {
@@ -45,10 +94,80 @@ pub fn get_udev_block_device(
     Ok(result)
 }
 
-/// Lookup the WWN from the udev db using the device node eg. /dev/sda
+/// Returns true if some other device, e.g., a devicemapper device, is
+/// stacked on top of the device.
+pub fn has_holders(device: Device) -> StratisResult<bool> {
+    let holders_dir = PathBuf::from(format!("/sys/dev/block/{}/holders", device));
+    Ok(fs::read_dir(holders_dir)?.next().is_some())
+}
+
+/// Parse the contents of a mountinfo file, returning the devices on which
+/// the mounted filesystems reside.
+fn parse_mountinfo(contents: &str) -> StratisResult<HashSet<Device>> {
+    libmount::mountinfo::Parser::new(contents.as_bytes())
+        .map(|mp| match mp {
+            Ok(mount) => Ok(Device {
+                major: mount.major as u32,
+                minor: mount.minor as u32,
+            }),
+            Err(e) => {
+                let err_msg = format!("Error during parsing mountinfo: {:?}", e);
+                Err(StratisError::Engine(ErrorEnum::Error, err_msg))
+            }
+        })
+        .collect()
+}
+
+/// The devices on which the filesystems mounted in this process's mount
+/// namespace reside.
+pub fn mounted_devices() -> StratisResult<HashSet<Device>> {
+    parse_mountinfo(&fs::read_to_string("/proc/self/mountinfo")?)
+}
+
+/// If device is a partition, find out whether the disk that contains it, or
+/// any other partition of that disk, is in use, i.e., has a mounted
+/// filesystem or some other device stacked on top of it. Returns the first
+/// such device found, or None if there is none or device is not a partition.
+pub fn partition_sibling_in_use(device: Device) -> StratisResult<Option<Device>> {
+    let sysfs_dir = PathBuf::from(format!("/sys/dev/block/{}", device));
+    if !sysfs_dir.join("partition").exists() {
+        return Ok(None);
+    }
+
+    let disk_dir = sysfs_dir.join("..");
+    let read_dev = |dir: &Path| -> StratisResult<Device> {
+        Ok(fs::read_to_string(dir.join("dev"))?.trim().parse::<Device>()?)
+    };
+
+    let mut related = vec![read_dev(&disk_dir)?];
+    for entry in fs::read_dir(&disk_dir)? {
+        let path = entry?.path();
+        if path.join("partition").exists() {
+            let sibling = read_dev(&path)?;
+            if sibling != device {
+                related.push(sibling);
+            }
+        }
+    }
+
+    let mounted = mounted_devices()?;
+    for dev in related {
+        if mounted.contains(&dev) || has_holders(dev)? {
+            return Ok(Some(dev));
+        }
+    }
+    Ok(None)
+}
+
+/// Lookup the WWN from the udev db using the device node eg. /dev/sda.
+/// If the device has no WWN, lookup its serial number instead.
 pub fn hw_lookup(dev_node_search: &Path) -> StratisResult<Option<String>> {
     let dev = get_udev_block_device(dev_node_search)?;
-    Ok(dev.and_then(|dev| dev.get("ID_WWN").and_then(|i| Some(i.clone()))))
+    Ok(dev.and_then(|dev| {
+        dev.get("ID_WWN")
+            .or_else(|| dev.get("ID_SERIAL"))
+            .cloned()
+    }))
 }
 
 /// Collect paths for all the block devices which are not individual multipath paths and which
@@ -103,3 +222,51 @@ pub fn get_stratis_block_devices() -> StratisResult<Vec<PathBuf>> {
         Ok(devices)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that a file is sparse exactly if it occupies less space on
+    /// disk than its length, and that only the provisioning modes of thinly
+    /// provisioned disks are recognized as such.
+    fn test_thin_backed_heuristics() {
+        assert!(is_sparse(4096, 0));
+        assert!(is_sparse(4097, 8));
+        assert!(!is_sparse(4096, 8));
+        assert!(!is_sparse(0, 0));
+
+        assert!(is_thin_provisioning_mode("unmap\n"));
+        assert!(is_thin_provisioning_mode("writesame_16\n"));
+        assert!(is_thin_provisioning_mode("writesame_10\n"));
+        assert!(!is_thin_provisioning_mode("full\n"));
+        assert!(!is_thin_provisioning_mode("disabled\n"));
+        assert!(!is_thin_provisioning_mode(""));
+    }
+
+    #[test]
+    /// Verify that the device of every mount is found, and that a line
+    /// without a valid device is rejected.
+    fn test_parse_mountinfo() {
+        let contents = "\
+22 1 253:0 / / rw,relatime shared:1 - xfs /dev/mapper/root rw
+40 22 8:1 / /boot rw,relatime shared:26 - ext4 /dev/sda1 rw
+41 22 8:1 /data /srv rw,relatime shared:27 - ext4 /dev/sda1 rw
+";
+        assert_eq!(
+            parse_mountinfo(contents).unwrap(),
+            vec![
+                Device {
+                    major: 253,
+                    minor: 0,
+                },
+                Device { major: 8, minor: 1 },
+            ].into_iter()
+                .collect::<HashSet<_>>()
+        );
+        assert!(parse_mountinfo("").unwrap().is_empty());
+        assert!(parse_mountinfo("22 1\n").is_err());
+        assert!(parse_mountinfo("22 1 sda1 / /\n").is_err());
+    }
+}
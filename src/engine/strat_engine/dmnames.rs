@@ -9,7 +9,7 @@ use std::fmt::Display;
 
 use devicemapper::{DmNameBuf, DmUuidBuf};
 
-use super::super::super::engine::{FilesystemUuid, PoolUuid};
+use super::super::super::engine::{DevUuid, FilesystemUuid, PoolUuid};
 
 const FORMAT_VERSION: u16 = 1;
 
@@ -183,3 +183,28 @@ pub fn format_backstore_ids(pool_uuid: PoolUuid, role: CacheRole) -> (DmNameBuf,
         DmUuidBuf::new(value).expect("FORMAT_VERSION display_length < 61"),
     )
 }
+
+/// Format a name & uuid for the dm-crypt device of a blockdev.
+///
+/// Prerequisite: len(format!("{}", FORMAT_VERSION)
+///             + len("stratis")                         7
+///             + len("private")                         7
+///             + len("crypt")                           5
+///             + num_dashes                             5
+///             + len(pool uuid)                         32
+///             + len(dev uuid)                          32
+///             < 128 (129 for UUID)
+///
+/// which is equivalent to len(format!("{}", FORMAT_VERSION) < 40 (41 for UUID)
+pub fn format_crypt_ids(pool_uuid: PoolUuid, dev_uuid: DevUuid) -> (DmNameBuf, DmUuidBuf) {
+    let value = format!(
+        "stratis-{}-private-{}-crypt-{}",
+        FORMAT_VERSION,
+        pool_uuid.simple(),
+        dev_uuid.simple()
+    );
+    (
+        DmNameBuf::new(value.clone()).expect("FORMAT_VERSION display_length < 40"),
+        DmUuidBuf::new(value).expect("FORMAT_VERSION display_length < 41"),
+    )
+}
@@ -17,7 +17,7 @@ use super::super::structures::Table;
 use super::super::types::{Name, PoolUuid, Redundancy, RenameAction};
 
 use super::backstore::device::is_stratis_device;
-use super::backstore::{find_all, get_metadata};
+use super::backstore::{find_all, get_metadata, ForceToken};
 #[cfg(test)]
 use super::cleanup::teardown_pools;
 use super::cmd::verify_binaries;
@@ -164,6 +164,7 @@ impl Engine for StratEngine {
         blockdev_paths: &[&Path],
         redundancy: Option<u16>,
         force: bool,
+        key_desc: Option<&str>,
     ) -> StratisResult<PoolUuid> {
         let redundancy = calculate_redundancy!(redundancy);
 
@@ -171,7 +172,13 @@ impl Engine for StratEngine {
             return Err(StratisError::Engine(ErrorEnum::AlreadyExists, name.into()));
         }
 
-        let (uuid, pool) = StratPool::initialize(name, blockdev_paths, redundancy, force)?;
+        let (uuid, pool) = StratPool::initialize(
+            name,
+            blockdev_paths,
+            redundancy,
+            ForceToken::from_user_flag(force),
+            key_desc,
+        )?;
 
         let name = Name::new(name.to_owned());
         devlinks::pool_added(&name)?;
@@ -351,6 +358,21 @@ impl Engine for StratEngine {
 
         Ok(())
     }
+
+    /// Every pool is shut down, even if shutting down another fails; the
+    /// first error encountered is returned.
+    fn shutdown(&mut self) -> StratisResult<()> {
+        let mut result = Ok(());
+        for (pool_name, _, pool) in &mut self.pools {
+            if let Err(err) = pool.shutdown() {
+                warn!("failed to shut down pool {}: {}", pool_name, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
 }
 
 #[cfg(test)]
@@ -368,7 +390,7 @@ mod test {
         let mut engine = StratEngine::initialize().unwrap();
 
         let name1 = "name1";
-        let uuid1 = engine.create_pool(&name1, paths, None, false).unwrap();
+        let uuid1 = engine.create_pool(&name1, paths, None, false, None).unwrap();
 
         let name2 = "name2";
         let action = engine.rename_pool(uuid1, name2).unwrap();
@@ -411,10 +433,10 @@ mod test {
         let mut engine = StratEngine::initialize().unwrap();
 
         let name1 = "name1";
-        let uuid1 = engine.create_pool(&name1, paths1, None, false).unwrap();
+        let uuid1 = engine.create_pool(&name1, paths1, None, false, None).unwrap();
 
         let name2 = "name2";
-        let uuid2 = engine.create_pool(&name2, paths2, None, false).unwrap();
+        let uuid2 = engine.create_pool(&name2, paths2, None, false, None).unwrap();
 
         assert!(engine.get_pool(uuid1).is_some());
         assert!(engine.get_pool(uuid2).is_some());
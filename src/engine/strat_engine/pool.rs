@@ -19,7 +19,7 @@ use super::super::types::{
 };
 use stratis::{ErrorEnum, StratisError, StratisResult};
 
-use super::backstore::{Backstore, StratBlockDev, MIN_MDA_SECTORS};
+use super::backstore::{Backstore, ForceToken, StratBlockDev, MIN_MDA_SECTORS};
 use super::serde_structs::{FlexDevsSave, PoolSave, Recordable};
 use super::thinpool::{ThinPool, ThinPoolSizeParams};
 
@@ -121,11 +121,13 @@ impl StratPool {
         name: &str,
         paths: &[&Path],
         redundancy: Redundancy,
-        force: bool,
+        force: Option<ForceToken>,
+        key_desc: Option<&str>,
     ) -> StratisResult<(PoolUuid, StratPool)> {
         let pool_uuid = Uuid::new_v4();
 
-        let mut backstore = Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, force)?;
+        let mut backstore =
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, force, key_desc)?;
 
         let thinpool = ThinPool::new(
             pool_uuid,
@@ -181,6 +183,13 @@ impl StratPool {
 
         let changed = thinpool.check(uuid, &mut backstore)?;
 
+        if metadata.backstore.data_devs.len() > 1 && backstore.datatier_metadata_redundancy() == 0 {
+            warn!(
+                "only one blockdev of pool {} holds an intact copy of its metadata",
+                uuid
+            );
+        }
+
         let mut pool = StratPool {
             backstore,
             redundancy: Redundancy::NONE,
@@ -210,6 +219,11 @@ impl StratPool {
         self.backstore.teardown()
     }
 
+    /// Quiesce the blockdevs of the pool before the daemon exits.
+    pub fn shutdown(&mut self) -> StratisResult<()> {
+        self.backstore.shutdown()
+    }
+
     pub fn has_filesystems(&self) -> bool {
         self.thin_pool.has_filesystems()
     }
@@ -290,6 +304,7 @@ impl Pool for StratPool {
         tier: BlockDevTier,
         force: bool,
     ) -> StratisResult<Vec<DevUuid>> {
+        let force = ForceToken::from_user_flag(force);
         let bdev_info = if tier == BlockDevTier::Cache {
             // If adding cache devices, must suspend the pool, since the cache
             // must be augmeneted with the new devices.
@@ -468,14 +483,14 @@ mod tests {
 
         let name1 = "name1";
         let (uuid1, mut pool1) =
-            StratPool::initialize(&name1, paths1, Redundancy::NONE, false).unwrap();
+            StratPool::initialize(&name1, paths1, Redundancy::NONE, None, None).unwrap();
         invariant(&pool1, &name1);
 
         let metadata1 = pool1.record(name1);
 
         let name2 = "name2";
         let (uuid2, mut pool2) =
-            StratPool::initialize(&name2, paths2, Redundancy::NONE, false).unwrap();
+            StratPool::initialize(&name2, paths2, Redundancy::NONE, None, None).unwrap();
         invariant(&pool2, &name2);
 
         let metadata2 = pool2.record(name2);
@@ -525,7 +540,13 @@ mod tests {
     fn test_empty_pool(paths: &[&Path]) -> () {
         assert_eq!(paths.len(), 0);
         assert!(
-            StratPool::initialize("stratis_test_pool", paths, Redundancy::NONE, true,).is_err()
+            StratPool::initialize(
+                "stratis_test_pool",
+                paths,
+                Redundancy::NONE,
+                Some(ForceToken::overwrite_foreign()),
+                None,
+            ).is_err()
         );
     }
 
@@ -551,7 +572,7 @@ mod tests {
         let name = "stratis-test-pool";
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let (uuid, mut pool) =
-            StratPool::initialize(&name, paths2, Redundancy::NONE, false).unwrap();
+            StratPool::initialize(&name, paths2, Redundancy::NONE, None, None).unwrap();
         devlinks::pool_added(&name).unwrap();
         invariant(&pool, &name);
 
@@ -19,6 +19,24 @@ use devicemapper::{Sectors, ThinDevId};
 
 use super::super::types::{DevUuid, FilesystemUuid};
 
+/// The newest version of the BlockDevSave schema, which this version of
+/// stratisd writes and is the newest it can read. Metadata that predates
+/// versioning of the schema carries no version and is read as version 1.
+/// Version 2 adds the key_description field of BlockDevSave and the
+/// alloc_chunk and min_devices fields of BackstoreSave, each of which a
+/// reader of version 1 would ignore, to the peril of the data.
+/// Metadata that sets none of them is still written as version 1, so that
+/// it remains readable by older versions of stratisd.
+pub const BLOCKDEV_SAVE_SCHEMA_VERSION: u32 = 2;
+
+/// The version of the BlockDevSave schema that this version of stratisd
+/// writes for metadata that uses none of the fields added since.
+const BASE_BLOCKDEV_SAVE_SCHEMA_VERSION: u32 = 1;
+
+fn default_blockdev_save_schema_version() -> u32 {
+    BASE_BLOCKDEV_SAVE_SCHEMA_VERSION
+}
+
 /// Implements saving struct data to a serializable form. The form should be
 /// sufficient, in conjunction with the environment, to reconstruct the
 /// saved struct in all its essentials.
@@ -36,11 +54,29 @@ pub struct PoolSave {
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockDevSave {
+    #[serde(default = "default_blockdev_save_schema_version")]
+    pub schema_version: u32,
     pub uuid: DevUuid,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user_info: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hardware_info: Option<String>,
+    /// The description of the key in the kernel keyring with which the
+    /// data on the blockdev is encrypted, if it is encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_description: Option<String>,
+}
+
+impl BlockDevSave {
+    /// The oldest version of the schema that records everything that is
+    /// set in this BlockDevSave.
+    pub fn required_schema_version(&self) -> u32 {
+        if self.key_description.is_some() {
+            BLOCKDEV_SAVE_SCHEMA_VERSION
+        } else {
+            BASE_BLOCKDEV_SAVE_SCHEMA_VERSION
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -70,9 +106,147 @@ pub struct BackstoreSave {
     pub data_segments: Vec<(Uuid, Sectors, Sectors)>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub meta_segments: Option<Vec<(Uuid, Sectors, Sectors)>>,
+    /// The unit in which space is allocated from the data tier, if other
+    /// than a single sector.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alloc_chunk: Option<Sectors>,
+    /// The fewest blockdevs that may be left in the data tier, if other
+    /// than one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_devices: Option<usize>,
+}
+
+impl BackstoreSave {
+    /// Raise the schema version of every blockdev to the newest if the
+    /// backstore records anything that only the newest records, so that
+    /// an older stratisd, which checks only the version of the blockdevs,
+    /// refuses it rather than ignoring it.
+    pub fn raise_schema_versions(&mut self) {
+        if self.alloc_chunk.is_some() || self.min_devices.is_some() {
+            for save in self.data_devs
+                .iter_mut()
+                .chain(self.cache_devs.iter_mut().flat_map(|devs| devs.iter_mut()))
+            {
+                save.schema_version = BLOCKDEV_SAVE_SCHEMA_VERSION;
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ThinPoolDevSave {
     pub data_block_size: Sectors,
 }
+
+#[cfg(test)]
+pub mod tests {
+    use serde_json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// A BlockDevSave of the newest schema version for the blockdev with
+    /// the given UUID, recording nothing else.
+    pub fn blockdev_save(uuid: DevUuid) -> BlockDevSave {
+        BlockDevSave {
+            schema_version: BLOCKDEV_SAVE_SCHEMA_VERSION,
+            uuid,
+            user_info: None,
+            hardware_info: None,
+            key_description: None,
+        }
+    }
+
+    #[test]
+    /// Verify that each optional field of a BlockDevSave survives a
+    /// serialization round-trip, and that its absence is also preserved.
+    fn test_blockdev_save_round_trip() {
+        let cases = vec![
+            (
+                "user_info",
+                BlockDevSave {
+                    user_info: Some("shelf2-bay7".into()),
+                    ..blockdev_save(Uuid::new_v4())
+                },
+            ),
+            (
+                "key_description",
+                BlockDevSave {
+                    key_description: Some("stratis-pool1".into()),
+                    ..blockdev_save(Uuid::new_v4())
+                },
+            ),
+        ];
+        for (field, save) in cases {
+            let bare = blockdev_save(save.uuid);
+            let json = serde_json::to_string(&bare).unwrap();
+            assert!(!json.contains(field));
+            assert_eq!(serde_json::from_str::<BlockDevSave>(&json).unwrap(), bare);
+
+            let json = serde_json::to_string(&save).unwrap();
+            assert!(json.contains(field));
+            assert_eq!(serde_json::from_str::<BlockDevSave>(&json).unwrap(), save);
+        }
+    }
+
+    #[test]
+    /// Verify that a BlockDevSave written before the schema was versioned
+    /// is read as version 1.
+    fn test_blockdev_save_unversioned() {
+        let uuid = Uuid::new_v4();
+        let json = format!("{{\"uuid\":\"{}\"}}", uuid);
+        let save = serde_json::from_str::<BlockDevSave>(&json).unwrap();
+        assert_eq!(save.schema_version, 1);
+        assert_eq!(save.uuid, uuid);
+    }
+
+    #[test]
+    /// Verify that a blockdev requires the newest schema version just when
+    /// it records a field that the oldest would ignore, and that metadata
+    /// that records a chunk size raises every blockdev to the newest.
+    fn test_required_schema_version() {
+        let save = || BlockDevSave {
+            schema_version: BASE_BLOCKDEV_SAVE_SCHEMA_VERSION,
+            user_info: Some("shelf2-bay7".into()),
+            ..blockdev_save(Uuid::new_v4())
+        };
+        assert_eq!(save().required_schema_version(), 1);
+        let mut encrypted = save();
+        encrypted.key_description = Some("stratis-pool1".into());
+        assert_eq!(
+            encrypted.required_schema_version(),
+            BLOCKDEV_SAVE_SCHEMA_VERSION
+        );
+
+        let mut backstore = BackstoreSave {
+            cache_devs: Some(vec![save()]),
+            cache_segments: None,
+            data_devs: vec![save(), save()],
+            data_segments: vec![],
+            meta_segments: None,
+            alloc_chunk: None,
+            min_devices: None,
+        };
+        backstore.raise_schema_versions();
+        assert!(backstore.data_devs.iter().all(|save| save.schema_version == 1));
+        backstore.alloc_chunk = Some(Sectors(2048));
+        backstore.raise_schema_versions();
+        assert!(backstore
+            .data_devs
+            .iter()
+            .chain(backstore.cache_devs.as_ref().unwrap().iter())
+            .all(|save| save.schema_version == BLOCKDEV_SAVE_SCHEMA_VERSION));
+
+        let mut backstore = BackstoreSave {
+            cache_devs: None,
+            cache_segments: None,
+            data_devs: vec![save()],
+            data_segments: vec![],
+            meta_segments: None,
+            alloc_chunk: None,
+            min_devices: Some(3),
+        };
+        backstore.raise_schema_versions();
+        assert_eq!(backstore.data_devs[0].schema_version, BLOCKDEV_SAVE_SCHEMA_VERSION);
+    }
+}
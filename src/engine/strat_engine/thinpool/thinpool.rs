@@ -772,7 +772,7 @@ impl ThinPool {
                 "Insufficient space to accomodate request for at least {}",
                 modulus
             );
-            Err(StratisError::Engine(ErrorEnum::Error, err_msg))
+            Err(StratisError::Engine(ErrorEnum::NotEnoughSpace, err_msg))
         }
     }
 
@@ -1169,7 +1169,7 @@ mod tests {
 
     use super::super::super::super::types::BlockDevTier;
 
-    use super::super::super::backstore::MIN_MDA_SECTORS;
+    use super::super::super::backstore::{ForceToken, MIN_MDA_SECTORS};
     use super::super::super::tests::{loopbacked, real};
 
     use super::super::filesystem::{fs_usage, FILESYSTEM_LOWATER};
@@ -1185,7 +1185,7 @@ mod tests {
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let (first_path, remaining_paths) = paths.split_at(1);
         let mut backstore =
-            Backstore::initialize(pool_uuid, &first_path, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, &first_path, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1243,7 +1243,12 @@ mod tests {
         };
         // Add block devices to the pool and run check() to extend
         backstore
-            .add_blockdevs(pool_uuid, &remaining_paths, BlockDevTier::Data, true)
+            .add_blockdevs(
+                pool_uuid,
+                &remaining_paths,
+                BlockDevTier::Data,
+                Some(ForceToken::overwrite_foreign()),
+            )
             .unwrap();
         pool.check(pool_uuid, &mut backstore).unwrap();
         // Verify the pool is back in a Good state
@@ -1283,7 +1288,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1388,7 +1393,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1435,7 +1440,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1495,7 +1500,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1545,7 +1550,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         let small_meta_size = MetaBlocks(16);
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         // Create a ThinPool with a very small meta device.
         let mut thin_pool = ThinPool::new(
             pool_uuid,
@@ -1604,7 +1609,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1666,7 +1671,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1740,7 +1745,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1787,7 +1792,7 @@ mod tests {
         let pool_uuid = Uuid::new_v4();
         devlinks::setup_devlinks(Vec::new().into_iter()).unwrap();
         let mut backstore =
-            Backstore::initialize(pool_uuid, paths2, MIN_MDA_SECTORS, false).unwrap();
+            Backstore::initialize(pool_uuid, paths2, MIN_MDA_SECTORS, None, None).unwrap();
         let mut pool = ThinPool::new(
             pool_uuid,
             &ThinPoolSizeParams::default(),
@@ -1838,7 +1843,7 @@ mod tests {
             .device()
             .expect("Space already allocated from backstore, backstore must have device");
         backstore
-            .add_blockdevs(pool_uuid, paths1, BlockDevTier::Cache, false)
+            .add_blockdevs(pool_uuid, paths1, BlockDevTier::Cache, None)
             .unwrap();
         let new_device = backstore
             .device()
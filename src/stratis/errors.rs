@@ -25,6 +25,14 @@ pub enum ErrorEnum {
     Busy,
     Invalid,
     NotFound,
+
+    /// Data read from a device failed an integrity check.
+    Corrupt,
+    /// There is not enough space to satisfy a request.
+    NotEnoughSpace,
+    /// A device or request is refused by a rule of Stratis, e.g., a device
+    /// that belongs to another pool may not be added without force.
+    PolicyViolation,
 }
 
 #[derive(Debug)]
@@ -31,3 +31,6 @@ class StratisdErrors(IntEnum):
     INTERNAL_ERROR = 5
     NIX_ERROR = 6
     NOT_FOUND = 7
+    CORRUPT = 8
+    NOT_ENOUGH_SPACE = 9
+    POLICY_VIOLATION = 10